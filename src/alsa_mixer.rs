@@ -0,0 +1,200 @@
+use crate::volume_backend::{MixerConfig, VolumeBackend};
+use alsa::mixer::{Mixer, Selem, SelemChannelId, SelemId};
+use log::*;
+use std::sync::Mutex;
+
+#[derive(thiserror::Error, Debug)]
+pub enum AlsaMixerError {
+    #[error("Can't open ALSA mixer of {device}: {source}")]
+    Open { device: String, source: alsa::Error },
+    #[error("No mixer control '{0}'")]
+    NoControl(String),
+    #[error("Mixer control '{0}' has no playback volume")]
+    NoPlaybackVolume(String),
+    #[error("Mixer control '{control}' has no channels {channels:?}")]
+    MissingChannels {
+        control: String,
+        channels: Vec<String>,
+    },
+    #[error("ALSA mixer: {0}")]
+    Alsa(#[from] alsa::Error),
+}
+
+/// Drives the same simple mixer control as `amixer` through alsa-lib, without spawning
+/// anything. Percentages are mapped onto the raw range the way amixer does it, so both report
+/// the same levels.
+pub struct AlsaMixerBackend {
+    mixer: Mutex<Mixer>,
+    config: MixerConfig,
+}
+
+impl AlsaMixerBackend {
+    pub fn open(config: MixerConfig) -> Result<Self, AlsaMixerError> {
+        let device = match &config.card {
+            Some(card) => format!("hw:{card}"),
+            None => "default".to_string(),
+        };
+        let mixer = Mixer::new(&device, false).map_err(|source| AlsaMixerError::Open {
+            device: device.clone(),
+            source,
+        })?;
+        Ok(AlsaMixerBackend {
+            mixer: Mutex::new(mixer),
+            config,
+        })
+    }
+
+    /// Runs `f` on the control, after picking up changes made by other programs.
+    fn with_selem<T>(
+        &self,
+        f: impl FnOnce(&Selem) -> Result<T, AlsaMixerError>,
+    ) -> Result<T, AlsaMixerError> {
+        let mixer = self.mixer.lock().unwrap();
+        mixer.handle_events()?;
+        let selem = mixer
+            .find_selem(&SelemId::new(&self.config.control, 0))
+            .ok_or_else(|| AlsaMixerError::NoControl(self.config.control.clone()))?;
+        if !selem.has_playback_volume() {
+            return Err(AlsaMixerError::NoPlaybackVolume(
+                self.config.control.clone(),
+            ));
+        }
+        f(&selem)
+    }
+
+    /// Playback channels of the control matching the configured names, all if none are given.
+    fn channels(&self, selem: &Selem) -> Result<Vec<SelemChannelId>, AlsaMixerError> {
+        let available: Vec<SelemChannelId> = SelemChannelId::all()
+            .iter()
+            .copied()
+            .filter(|channel| selem.has_playback_channel(*channel))
+            .collect();
+        if self.config.channels.is_empty() {
+            return Ok(available);
+        }
+        let mut missing = Vec::new();
+        let mut channels = Vec::new();
+        for name in &self.config.channels {
+            let matching: Vec<SelemChannelId> = available
+                .iter()
+                .copied()
+                .filter(|channel| channel_matches(*channel, name))
+                .collect();
+            if matching.is_empty() {
+                missing.push(name.clone());
+            }
+            channels.extend(matching);
+        }
+        if !missing.is_empty() {
+            return Err(AlsaMixerError::MissingChannels {
+                control: self.config.control.clone(),
+                channels: missing,
+            });
+        }
+        Ok(channels)
+    }
+}
+
+/// Same matching as amixer's channel names, e.g. `front` matches `Front Left` and `Front Right`.
+fn channel_matches(channel: SelemChannelId, name: &str) -> bool {
+    Selem::channel_name(channel).is_ok_and(|channel_name| {
+        channel_name
+            .replace(' ', "")
+            .to_lowercase()
+            .starts_with(&name.to_lowercase())
+    })
+}
+
+fn raw_to_percent(raw: i64, (min, max): (i64, i64)) -> i32 {
+    if max <= min {
+        return 0;
+    }
+    ((raw - min) as f64 * 100.0 / (max - min) as f64).round() as i32
+}
+
+fn percent_to_raw(percent: i32, (min, max): (i64, i64)) -> i64 {
+    (percent as f64 * (max - min) as f64 / 100.0).ceil() as i64 + min
+}
+
+impl VolumeBackend for AlsaMixerBackend {
+    fn name(&self) -> &'static str {
+        "alsa"
+    }
+
+    fn validate(&self) -> Result<(), anyhow::Error> {
+        let volume = self.get_volume()?;
+        info!(
+            "Using ALSA mixer control '{}' at {volume}%",
+            self.config.control
+        );
+        Ok(())
+    }
+
+    /// Volume of the first channel.
+    fn get_volume(&self) -> Result<i32, anyhow::Error> {
+        Ok(self.with_selem(|selem| {
+            let channel = *self
+                .channels(selem)?
+                .first()
+                .ok_or_else(|| AlsaMixerError::NoPlaybackVolume(self.config.control.clone()))?;
+            let raw = selem.get_playback_volume(channel)?;
+            Ok(raw_to_percent(raw, selem.get_playback_volume_range()))
+        })?)
+    }
+
+    fn set_volume(&self, percent: i32) -> Result<(), anyhow::Error> {
+        Ok(self.with_selem(|selem| {
+            let raw = percent_to_raw(percent, selem.get_playback_volume_range());
+            if self.config.channels.is_empty() {
+                selem.set_playback_volume_all(raw)?;
+            } else {
+                for channel in self.channels(selem)? {
+                    selem.set_playback_volume(channel, raw)?;
+                }
+            }
+            Ok(())
+        })?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::volume_backend::check_volume_backend;
+
+    #[test]
+    fn percent_conversion_test() {
+        assert_eq!(raw_to_percent(0, (0, 255)), 0);
+        assert_eq!(raw_to_percent(128, (0, 255)), 50);
+        assert_eq!(raw_to_percent(-2000, (-10239, 400)), 77);
+        assert_eq!(percent_to_raw(50, (0, 255)), 128);
+        assert_eq!(percent_to_raw(100, (-10239, 400)), 400);
+        for percent in 0..=100 {
+            let range = (0, 65536);
+            assert_eq!(
+                raw_to_percent(percent_to_raw(percent, range), range),
+                percent
+            );
+        }
+    }
+
+    #[test]
+    fn channel_matches_test() {
+        assert!(channel_matches(SelemChannelId::FrontLeft, "front"));
+        assert!(channel_matches(SelemChannelId::FrontRight, "frontright"));
+        assert!(!channel_matches(SelemChannelId::FrontLeft, "frontright"));
+        assert!(!channel_matches(SelemChannelId::RearLeft, "front"));
+    }
+
+    #[test]
+    #[ignore = "drives the Master control of the default sound card"]
+    fn alsa_backend_test() {
+        let backend = AlsaMixerBackend::open(MixerConfig {
+            card: None,
+            control: "Master".to_string(),
+            channels: Vec::new(),
+        })
+        .unwrap();
+        check_volume_backend(&backend);
+    }
+}
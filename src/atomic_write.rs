@@ -0,0 +1,35 @@
+use std::io::Write;
+use std::path::Path;
+
+/// Replaces the file with `contents` through a temporary file next to it, so a crash midway
+/// leaves the old file intact instead of a truncated one. Missing parent directories are
+/// created.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> Result<(), anyhow::Error> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    std::fs::create_dir_all(dir)?;
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    file.write_all(contents)?;
+    file.persist(path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn write_atomically_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state/volume.json");
+        write_atomically(&path, b"old").unwrap();
+        write_atomically(&path, b"new").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(
+            std::fs::read_dir(path.parent().unwrap()).unwrap().count(),
+            1
+        );
+    }
+}
@@ -1,124 +1,329 @@
-use log::info;
-use rand::seq::SliceRandom;
-use rand::Rng;
-use serde::{Deserialize, Deserializer};
-use std::iter;
+use chrono::{DateTime, Local};
+use log::{info, warn};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::ops::Add;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime};
 
+use crate::nick_aliases::NickAliases;
+use crate::phrase_templates::PhraseTemplates;
 use crate::resource_catalogue::ResourceCatalogue;
+use crate::tts::Tts;
+
+/// What a typed nick was announced as.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct NickResolution {
+    pub nick: String,
+    pub sample_key: String,
+    /// False if there's no sample for the key, "unknown" is played instead.
+    pub has_sample: bool,
+    pub last_seen: DateTime<Local>,
+    /// Call-outs in a row the sample key was missing in, 0 once it was left out of one or the
+    /// time window passed.
+    pub missing_streak: u32,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SampleSource {
+    Recorded,
+    /// Synthesized, the nick has no recording.
+    Speech,
+    /// The "unknown" sample played for a nick with neither recording nor speech.
+    Unknown,
+    /// Nothing to play, left out of the playlist.
+    Missing,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct PlaylistEntry {
+    pub sample_key: String,
+    pub file: Option<String>,
+    pub source: SampleSource,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct NickPreview {
+    pub nick: String,
+    pub sample_key: String,
+    pub source: SampleSource,
+    /// Counting the call-out being previewed.
+    pub missing_streak: u32,
+    /// Shoutout level the streak reached, none for the plain shoutout.
+    pub level: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct PlaylistPreview {
+    pub nicks: Vec<NickPreview>,
+    pub entries: Vec<PlaylistEntry>,
+}
+
+/// Consecutive call-outs a sample key was missing in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct MissingStreak {
+    count: u32,
+    last_call: SystemTime,
+}
 
 struct AutogrzybkeImpl {
     resources: Arc<ResourceCatalogue>,
+    aliases: NickAliases,
+    tts: Option<Tts>,
     recent_usage_time_window: Duration,
     recent_usage_timestamps: Vec<SystemTime>,
     last_missing_list: Vec<String>,
-    prefix_chance_percent: u64,
-    suffix_chance_percent: u64,
+    /// By sample key, only of the nicks missing in the last call-out.
+    missing_streaks: HashMap<String, MissingStreak>,
+    /// By nick as typed.
+    nick_resolutions: BTreeMap<String, NickResolution>,
+    templates: PhraseTemplates,
 }
 impl AutogrzybkeImpl {
     fn new(
         resources: Arc<ResourceCatalogue>,
-        prefix_chance_percent: u64,
-        suffix_chance_percent: u64,
+        aliases: NickAliases,
+        tts: Option<Tts>,
+        templates: PhraseTemplates,
+        window: Duration,
     ) -> Self {
         AutogrzybkeImpl {
-            resources: resources,
-            recent_usage_time_window: Duration::from_secs(60 * 15),
+            resources,
+            aliases,
+            tts,
+            recent_usage_time_window: window,
             recent_usage_timestamps: Vec::new(),
             last_missing_list: Vec::new(),
-            prefix_chance_percent: prefix_chance_percent,
-            suffix_chance_percent: suffix_chance_percent,
+            missing_streaks: HashMap::new(),
+            nick_resolutions: BTreeMap::new(),
+            templates,
         }
     }
 
-    fn get_usage_count(&mut self) -> i64 {
-        let now = SystemTime::now();
+    /// Remembered for `get_nick_resolutions`.
+    fn record_resolution(&mut self, nick: &NickPreview) {
+        let resolution = NickResolution {
+            nick: nick.nick.clone(),
+            sample_key: nick.sample_key.clone(),
+            has_sample: nick.source == SampleSource::Recorded,
+            last_seen: Local::now(),
+            missing_streak: nick.missing_streak,
+        };
+        info!("Nick resolved: {resolution:?}");
+        self.nick_resolutions
+            .insert(resolution.nick.clone(), resolution);
+    }
+
+    fn sample_keys(&self, req: &AutogrzybkeRequest) -> Vec<String> {
+        req.missing
+            .iter()
+            .map(|nick| self.aliases.resolve(nick))
+            .collect()
+    }
+
+    /// Sample keys of the request with neither a sample nor synthesized speech yet, with the
+    /// engine to speak them.
+    fn unspoken_nicks(&self, req: &AutogrzybkeRequest) -> Option<(Tts, Vec<String>)> {
+        let tts = self.tts.as_ref()?;
+        let sample_keys: Vec<String> = self
+            .sample_keys(req)
+            .into_iter()
+            .filter(|key| self.resources.random_sample(key).is_none() && tts.cached(key).is_none())
+            .collect();
+        (!sample_keys.is_empty()).then(|| (tts.clone(), sample_keys))
+    }
+
+    /// Speech synthesized before for the nicks without samples.
+    fn cached_speech(&self, sample_keys: &[String]) -> HashMap<String, String> {
+        let Some(tts) = &self.tts else {
+            return HashMap::new();
+        };
+        sample_keys
+            .iter()
+            .filter(|key| self.resources.random_sample(key).is_none())
+            .filter_map(|key| {
+                let path = tts.cached(key)?;
+                Some((key.clone(), path.to_string_lossy().into_owned()))
+            })
+            .collect()
+    }
+
+    /// Uses within the time window, counting the one being planned.
+    fn get_usage_count(&self, now: SystemTime) -> i64 {
+        self.recent_usage_timestamps
+            .iter()
+            .filter(|timestamp| timestamp.add(self.recent_usage_time_window) > now)
+            .count() as i64
+            + 1
+    }
+
+    /// The missing streak of the sample key if it's missing in a call-out made `now`. It
+    /// breaks if the previous call-out is older than the time window.
+    fn get_missing_streak(&self, sample_key: &str, now: SystemTime) -> u32 {
+        match self.missing_streaks.get(sample_key) {
+            Some(streak) if streak.last_call.add(self.recent_usage_time_window) > now => {
+                streak.count + 1
+            }
+            _ => 1,
+        }
+    }
+
+    /// Streaks of the sample keys left out of the call-out are dropped.
+    fn record_missing_streaks(&mut self, nicks: &[NickPreview], now: SystemTime) {
+        self.missing_streaks = nicks
+            .iter()
+            .map(|nick| {
+                let streak = MissingStreak {
+                    count: nick.missing_streak,
+                    last_call: now,
+                };
+                (nick.sample_key.clone(), streak)
+            })
+            .collect();
+    }
+
+    fn record_usage(&mut self, now: SystemTime) {
         self.recent_usage_timestamps.push(now);
         self.recent_usage_timestamps
             .retain(|timestamp| timestamp.add(self.recent_usage_time_window) > now);
-        self.recent_usage_timestamps.len() as i64
     }
 
     fn generate_playlist(&mut self, req: AutogrzybkeRequest) -> Vec<String> {
         info!("AUTOGRZYBKE REQUEST: {req:?}");
+        let now = SystemTime::now();
+        let preview = self.preview_playlist_at(&req, now);
         if req.missing.is_empty() {
-            self.generate_ready_playlist()
+            self.recent_usage_timestamps.clear();
+            self.last_missing_list.clear();
+            self.missing_streaks.clear();
         } else {
-            self.generate_waiting_playlist(req)
+            self.last_missing_list = req.missing.clone();
+            self.last_missing_list.sort_unstable();
+            if !req.skip_interlude {
+                self.record_usage(now);
+            }
+            self.record_missing_streaks(&preview.nicks, now);
+            for nick in &preview.nicks {
+                self.record_resolution(nick);
+            }
         }
+        preview
+            .entries
+            .into_iter()
+            .flat_map(|entry| entry.file)
+            .collect()
     }
 
-    fn generate_ready_playlist(&mut self) -> Vec<String> {
-        self.recent_usage_timestamps.clear();
-        self.last_missing_list.clear();
-        ["noise", "everyone", "ready"]
-            .iter()
-            .flat_map(|sample| self.resources.random_sample(sample))
-            .collect()
+    /// The playlist `generate_playlist` would make, without remembering anything. Nothing is
+    /// synthesized, nicks spoken before show as speech, the others as unknown.
+    fn preview_playlist(&self, req: &AutogrzybkeRequest) -> PlaylistPreview {
+        self.preview_playlist_at(req, SystemTime::now())
     }
 
-    fn generate_waiting_playlist(&mut self, req: AutogrzybkeRequest) -> Vec<String> {
-        self.last_missing_list = req.missing.clone();
-        self.last_missing_list.sort_unstable();
-        let prefix_chance_percent = self.prefix_chance_percent;
-        let suffix_chance_percent = self.suffix_chance_percent;
-        let mut rng = rand::rng();
-        let mut missing = req
-            .missing
+    fn preview_playlist_at(&self, req: &AutogrzybkeRequest, now: SystemTime) -> PlaylistPreview {
+        if req.missing.is_empty() {
+            self.preview_ready_playlist(req)
+        } else {
+            self.preview_waiting_playlist(req, now)
+        }
+    }
+
+    fn preview_ready_playlist(&self, req: &AutogrzybkeRequest) -> PlaylistPreview {
+        let entries = self
+            .templates
+            .ready_sequence(req)
             .iter()
-            .map(|nick| {
-                let mut shoutout = Vec::new();
-                let shall_add_prefix =
-                    rng.random_range(0..100) < prefix_chance_percent && !req.skip_prefix;
-                let shall_add_suffix =
-                    rng.random_range(0..100) < suffix_chance_percent && !req.skip_suffix;
-
-                if shall_add_prefix || shall_add_suffix {
-                    shoutout.push("silence".to_string());
+            .map(|sample| {
+                let file = self.resources.random_sample(sample);
+                PlaylistEntry {
+                    sample_key: sample.to_string(),
+                    source: match file {
+                        Some(_) => SampleSource::Recorded,
+                        None => SampleSource::Missing,
+                    },
+                    file,
                 }
-                if shall_add_prefix {
-                    shoutout.push("prefix".to_string());
-                }
-                shoutout.push(nick.clone());
-                if shall_add_suffix {
-                    shoutout.push("suffix".to_string());
-                }
-                if shall_add_prefix || shall_add_suffix {
-                    shoutout.push("silence".to_string());
-                }
-                shoutout
             })
-            .chain(
-                iter::repeat(vec!["kurwa".to_string()]).take(if !req.skip_interlude {
-                    0.max((self.get_usage_count() - 1) / 2 - 1) as usize
-                } else {
-                    0
-                }),
-            )
-            .collect::<Vec<Vec<String>>>();
-        missing.shuffle(&mut rng);
-        let mut words = vec!["noise".to_string()];
-        words.extend(missing.into_iter().flatten());
-        words.extend(if !req.skip_lobby {
-            Some("lobby".to_string())
-        } else {
-            None
-        });
-        words
+            .collect();
+        PlaylistPreview {
+            nicks: Vec::new(),
+            entries,
+        }
+    }
+
+    fn preview_waiting_playlist(
+        &self,
+        req: &AutogrzybkeRequest,
+        now: SystemTime,
+    ) -> PlaylistPreview {
+        let sample_keys = self.sample_keys(req);
+        let spoken_nicks = self.cached_speech(&sample_keys);
+        let streaks: Vec<(String, u32)> = sample_keys
             .iter()
-            .flat_map(|sample| {
-                self.resources
-                    .random_sample(sample)
-                    .or_else(|| self.resources.random_sample("unknown"))
+            .map(|sample_key| {
+                let streak = self.get_missing_streak(sample_key, now);
+                (sample_key.clone(), streak)
             })
-            .collect()
+            .collect();
+        let words = self
+            .templates
+            .waiting_sequence(&streaks, self.get_usage_count(now), req);
+        let nicks = req
+            .missing
+            .iter()
+            .zip(streaks)
+            .map(|(nick, (sample_key, streak))| NickPreview {
+                nick: nick.clone(),
+                source: self.playlist_entry(&sample_key, &spoken_nicks).source,
+                missing_streak: streak,
+                level: self.templates.level_name(streak).map(String::from),
+                sample_key,
+            })
+            .collect();
+        let entries = words
+            .iter()
+            .map(|sample| self.playlist_entry(sample, &spoken_nicks))
+            .collect();
+        PlaylistPreview { nicks, entries }
+    }
+
+    /// A random recording of the sample, then speech, then the "unknown" sample.
+    fn playlist_entry(
+        &self,
+        sample_key: &str,
+        spoken_nicks: &HashMap<String, String>,
+    ) -> PlaylistEntry {
+        let (file, source) = if let Some(file) = self.resources.random_sample(sample_key) {
+            (Some(file), SampleSource::Recorded)
+        } else if let Some(file) = spoken_nicks.get(sample_key) {
+            (Some(file.clone()), SampleSource::Speech)
+        } else if let Some(file) = self.resources.random_sample("unknown") {
+            (Some(file), SampleSource::Unknown)
+        } else {
+            (None, SampleSource::Missing)
+        };
+        PlaylistEntry {
+            sample_key: sample_key.to_string(),
+            file,
+            source,
+        }
     }
 
     fn get_last_missing(&self) -> Vec<String> {
         self.last_missing_list.clone()
     }
+
+    fn get_nick_resolutions(&self) -> Vec<NickResolution> {
+        let now = SystemTime::now();
+        self.nick_resolutions
+            .values()
+            .map(|resolution| NickResolution {
+                missing_streak: self.get_missing_streak(&resolution.sample_key, now) - 1,
+                ..resolution.clone()
+            })
+            .collect()
+    }
 }
 
 pub struct Autogrzybke {
@@ -127,24 +332,51 @@ pub struct Autogrzybke {
 impl Autogrzybke {
     pub fn new(
         resources: Arc<ResourceCatalogue>,
-        prefix_chance_percent: u64,
-        suffix_chance_percent: u64,
+        aliases: NickAliases,
+        tts: Option<Tts>,
+        templates: PhraseTemplates,
+        window: Duration,
     ) -> Self {
         Autogrzybke {
             autogrzybke_impl: Mutex::new(AutogrzybkeImpl::new(
-                resources,
-                prefix_chance_percent,
-                suffix_chance_percent,
+                resources, aliases, tts, templates, window,
             )),
         }
     }
-    pub fn generate_playlist(&self, req: AutogrzybkeRequest) -> Vec<String> {
+    /// Nicks without samples are synthesized first, outside the lock, as that runs the TTS
+    /// engine and ffmpeg.
+    pub async fn generate_playlist(&self, req: AutogrzybkeRequest) -> Vec<String> {
+        let unspoken = self.autogrzybke_impl.lock().unwrap().unspoken_nicks(&req);
+        if let Some((tts, sample_keys)) = unspoken {
+            let synthesis = tokio::task::spawn_blocking(move || {
+                for key in sample_keys {
+                    if let Err(e) = tts.synthesize(&key) {
+                        warn!("Failed to synthesize {key:?}: {e:#}");
+                    }
+                }
+            });
+            if let Err(e) = synthesis.await {
+                warn!("Speech synthesis failed: {e:#}");
+            }
+        }
         self.autogrzybke_impl.lock().unwrap().generate_playlist(req)
     }
 
     pub fn get_last_missing(&self) -> Vec<String> {
         self.autogrzybke_impl.lock().unwrap().get_last_missing()
     }
+
+    /// Doesn't count as a use and isn't remembered in the last missing list. Doesn't synthesize
+    /// either, so it's quick.
+    pub fn preview_playlist(&self, req: &AutogrzybkeRequest) -> PlaylistPreview {
+        self.autogrzybke_impl.lock().unwrap().preview_playlist(req)
+    }
+
+    /// Every nick announced so far with the sample key it resolved to and its current missing
+    /// streak, sorted by nick.
+    pub fn get_nick_resolutions(&self) -> Vec<NickResolution> {
+        self.autogrzybke_impl.lock().unwrap().get_nick_resolutions()
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -170,3 +402,218 @@ where
     let text = String::deserialize(deserializer)?;
     Ok(text.split_whitespace().map(String::from).collect())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn preview_remembers_nothing_test() {
+        let dir = tempfile::tempdir().unwrap();
+        for file in ["noise1.mp3", "kuba1.mp3", "unknown1.mp3"] {
+            std::fs::write(dir.path().join(file), b"").unwrap();
+        }
+        let autogrzybke = Autogrzybke::new(
+            Arc::new(ResourceCatalogue::try_from_dir_path(dir.path()).unwrap()),
+            NickAliases::parse("kuba: [jakub]").unwrap(),
+            None,
+            PhraseTemplates::parse(
+                r#"
+ready: [noise, everyone, ready]
+waiting:
+  intro: [noise]
+  shoutout: {parts: [$nick]}
+  outro: [lobby]
+"#,
+            )
+            .unwrap(),
+            Duration::from_secs(60 * 15),
+        );
+        let req = AutogrzybkeRequest {
+            missing: vec!["Jakub".to_string(), "zbych".to_string()],
+            skip_lobby: false,
+            skip_prefix: false,
+            skip_suffix: false,
+            skip_interlude: false,
+        };
+        let preview = autogrzybke.preview_playlist(&req);
+        assert_eq!(
+            preview
+                .nicks
+                .iter()
+                .map(|nick| (nick.sample_key.as_str(), nick.source))
+                .collect::<Vec<_>>(),
+            [
+                ("kuba", SampleSource::Recorded),
+                ("zbych", SampleSource::Unknown)
+            ]
+        );
+        // Nicks are shuffled.
+        let mut entries: Vec<_> = preview
+            .entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry.sample_key.as_str(),
+                    entry.source == SampleSource::Recorded,
+                )
+            })
+            .collect();
+        entries.sort();
+        assert_eq!(
+            entries,
+            [
+                ("kuba", true),
+                ("lobby", false),
+                ("noise", true),
+                ("zbych", false)
+            ]
+        );
+        assert!(autogrzybke.get_last_missing().is_empty());
+        assert!(autogrzybke.get_nick_resolutions().is_empty());
+        assert!(autogrzybke
+            .autogrzybke_impl
+            .lock()
+            .unwrap()
+            .recent_usage_timestamps
+            .is_empty());
+
+        assert_eq!(autogrzybke.generate_playlist(req).await.len(), 4);
+        assert_eq!(autogrzybke.get_last_missing(), ["Jakub", "zbych"]);
+        assert_eq!(autogrzybke.get_nick_resolutions().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn missing_streak_test() {
+        let dir = tempfile::tempdir().unwrap();
+        for file in ["kuba1.mp3", "zbych1.mp3", "annoyed1.mp3"] {
+            std::fs::write(dir.path().join(file), b"").unwrap();
+        }
+        let autogrzybke = Autogrzybke::new(
+            Arc::new(ResourceCatalogue::try_from_dir_path(dir.path()).unwrap()),
+            NickAliases::parse("kuba: [jakub]").unwrap(),
+            None,
+            PhraseTemplates::parse(
+                r#"
+ready: []
+waiting:
+  shoutout:
+    parts: [$nick]
+    levels: [{name: annoyed, from_streak: 2, parts: [annoyed, $nick]}]
+"#,
+            )
+            .unwrap(),
+            Duration::from_secs(60 * 15),
+        );
+        let req = |missing: &str| AutogrzybkeRequest {
+            missing: missing.split_whitespace().map(String::from).collect(),
+            skip_lobby: false,
+            skip_prefix: false,
+            skip_suffix: false,
+            skip_interlude: false,
+        };
+        let streaks = |preview: PlaylistPreview| {
+            preview
+                .nicks
+                .into_iter()
+                .map(|nick| (nick.sample_key, nick.missing_streak, nick.level))
+                .collect::<Vec<_>>()
+        };
+        let annoyed = Some("annoyed".to_string());
+
+        assert_eq!(
+            autogrzybke.generate_playlist(req("kuba zbych")).await.len(),
+            2
+        );
+        assert_eq!(
+            streaks(autogrzybke.preview_playlist(&req("jakub zbych"))),
+            [
+                ("kuba".to_string(), 2, annoyed.clone()),
+                ("zbych".to_string(), 2, annoyed.clone())
+            ]
+        );
+        // Previews don't count.
+        assert_eq!(autogrzybke.generate_playlist(req("jakub")).await.len(), 2);
+        assert_eq!(
+            autogrzybke.generate_playlist(req("kuba zbych")).await.len(),
+            3
+        );
+        assert_eq!(
+            autogrzybke
+                .get_nick_resolutions()
+                .iter()
+                .map(|resolution| (resolution.nick.as_str(), resolution.missing_streak))
+                .collect::<Vec<_>>(),
+            [("jakub", 3), ("kuba", 3), ("zbych", 1)]
+        );
+
+        // The streak breaks after the time window.
+        {
+            let mut autogrzybke_impl = autogrzybke.autogrzybke_impl.lock().unwrap();
+            let streak = autogrzybke_impl.missing_streaks.get_mut("kuba").unwrap();
+            streak.last_call -= Duration::from_secs(60 * 16);
+        }
+        assert_eq!(
+            streaks(autogrzybke.preview_playlist(&req("kuba"))),
+            [("kuba".to_string(), 1, None)]
+        );
+
+        autogrzybke.generate_playlist(req("")).await;
+        assert_eq!(
+            streaks(autogrzybke.preview_playlist(&req("zbych"))),
+            [("zbych".to_string(), 1, None)]
+        );
+    }
+
+    #[tokio::test]
+    async fn speech_test() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("unknown1.mp3"), b"").unwrap();
+        let tts = Tts {
+            engine: crate::tts::TtsEngine::EspeakNg,
+            // The converted mp3 is the empty temporary file.
+            program_path: Some("true".to_string()),
+            voice: None,
+            language: "pl".to_string(),
+            cache_dir: dir.path().join("tts"),
+            max_cached_files: 10,
+            ffmpeg_path: "true".to_string(),
+        };
+        let autogrzybke = Autogrzybke::new(
+            Arc::new(ResourceCatalogue::try_from_dir_path(dir.path()).unwrap()),
+            NickAliases::default(),
+            Some(tts.clone()),
+            PhraseTemplates::parse("{ready: [], waiting: {shoutout: {parts: [$nick]}}}").unwrap(),
+            Duration::from_secs(60 * 15),
+        );
+        let req = || AutogrzybkeRequest {
+            missing: vec!["zbych".to_string()],
+            skip_lobby: false,
+            skip_prefix: false,
+            skip_suffix: false,
+            skip_interlude: false,
+        };
+        let sources = |preview: PlaylistPreview| {
+            preview
+                .entries
+                .into_iter()
+                .map(|entry| entry.source)
+                .collect::<Vec<_>>()
+        };
+
+        // Previews don't synthesize.
+        assert_eq!(
+            sources(autogrzybke.preview_playlist(&req())),
+            [SampleSource::Unknown]
+        );
+        assert_eq!(tts.cached("zbych"), None);
+
+        let playlist = autogrzybke.generate_playlist(req()).await;
+        let speech = tts.cached("zbych").unwrap();
+        assert_eq!(playlist, [speech.to_string_lossy()]);
+        assert_eq!(
+            sources(autogrzybke.preview_playlist(&req())),
+            [SampleSource::Speech]
+        );
+    }
+}
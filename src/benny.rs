@@ -1,4 +1,4 @@
-use crate::player::Player;
+use crate::player::{ContentSource, Player};
 use log::*;
 use rand::Rng;
 use std::sync::Arc;
@@ -10,7 +10,7 @@ pub struct Benny {
 impl Benny {
     pub fn new(player: Arc<Player>, benny_abs_path: Option<String>) -> Self {
         Benny {
-            player: player,
+            player,
             file_path: benny_abs_path,
         }
     }
@@ -21,7 +21,11 @@ impl Benny {
                 let seek = rand::rng().random_range(0..30000) as i64;
                 info!("Toggle benny, seek {}ms", seek);
                 self.player
-                    .toggle_play(file_path.clone(), chrono::Duration::milliseconds(seek))
+                    .toggle_play(
+                        file_path.clone(),
+                        chrono::Duration::milliseconds(seek),
+                        ContentSource::Benny,
+                    )
                     .map_err(|e| anyhow::anyhow!("{}", e))
             }
             None => {
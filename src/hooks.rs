@@ -0,0 +1,237 @@
+use crate::playback_backend::{
+    PlaybackBackend, PlaybackProcess, PlaybackSource, PositionQuery, SeekTarget,
+};
+use anyhow::anyhow;
+use log::*;
+use std::process::{Command, ExitStatus};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+/// Predefined hook sets, extended by hooks given on the command line.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum HookProfile {
+    /// Restart raspotify.service before playback, so it releases the audio card.
+    Raspotify,
+    None,
+}
+
+/// Shell commands run around playback. They run in the background, one after another, so
+/// playback never waits for them. Failures are logged, they never prevent playback.
+#[derive(Debug, Default, PartialEq)]
+pub struct PlaybackHooks {
+    pre_play: Vec<String>,
+    post_stop: Vec<String>,
+}
+
+impl PlaybackHooks {
+    pub fn new(profile: HookProfile, pre_play: Vec<String>, post_stop: Vec<String>) -> Self {
+        let mut hooks = match profile {
+            HookProfile::Raspotify => PlaybackHooks {
+                pre_play: vec![
+                    "sudo systemctl reset-failed raspotify.service".to_string(),
+                    "sudo systemctl restart raspotify.service".to_string(),
+                ],
+                post_stop: Vec::new(),
+            },
+            HookProfile::None => PlaybackHooks::default(),
+        };
+        hooks.pre_play.extend(pre_play);
+        hooks.post_stop.extend(post_stop);
+        hooks
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pre_play.is_empty() && self.post_stop.is_empty()
+    }
+
+    fn run_pre_play(&self) -> Option<JoinHandle<()>> {
+        run_hooks("pre-play", &self.pre_play)
+    }
+
+    fn run_post_stop(&self) -> Option<JoinHandle<()>> {
+        run_hooks("post-stop", &self.post_stop)
+    }
+}
+
+/// Runs the commands in order on a thread of their own, which logs how each one exited.
+fn run_hooks(kind: &'static str, commands: &[String]) -> Option<JoinHandle<()>> {
+    if commands.is_empty() {
+        return None;
+    }
+    let commands = commands.to_vec();
+    std::thread::Builder::new()
+        .name(format!("{kind} hooks"))
+        .spawn(move || {
+            for command in commands {
+                match run_hook(&command) {
+                    Ok(()) => info!("{kind} hook `{command}` succeeded"),
+                    Err(e) => warn!("{kind} hook `{command}` failed: {e:#}"),
+                }
+            }
+        })
+        .inspect_err(|e| error!("Failed to start {kind} hooks: {e}"))
+        .ok()
+}
+
+fn run_hook(command: &str) -> Result<(), anyhow::Error> {
+    let exit_status = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .status()
+        .map_err(|e| anyhow!(e).context("Failed to start hook"))?;
+    if exit_status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!("{exit_status}"))
+    }
+}
+
+/// Wraps a backend so that pre-play hooks run before each process starts and post-stop hooks
+/// after it is killed or exits on its own.
+pub struct HookedBackend {
+    backend: Box<dyn PlaybackBackend>,
+    hooks: Arc<PlaybackHooks>,
+}
+
+impl HookedBackend {
+    pub fn wrap(
+        backend: Box<dyn PlaybackBackend>,
+        hooks: PlaybackHooks,
+    ) -> Box<dyn PlaybackBackend> {
+        if hooks.is_empty() {
+            return backend;
+        }
+        Box::new(HookedBackend {
+            backend,
+            hooks: Arc::new(hooks),
+        })
+    }
+}
+
+impl PlaybackBackend for HookedBackend {
+    fn name(&self) -> &'static str {
+        self.backend.name()
+    }
+
+    fn start(
+        &self,
+        source: &PlaybackSource,
+        gain_db: f64,
+    ) -> Result<Box<dyn PlaybackProcess>, std::io::Error> {
+        self.hooks.run_pre_play();
+        Ok(Box::new(HookedProcess {
+            process: self.backend.start(source, gain_db)?,
+            hooks: self.hooks.clone(),
+            stopped: false,
+        }))
+    }
+}
+
+struct HookedProcess {
+    process: Box<dyn PlaybackProcess>,
+    hooks: Arc<PlaybackHooks>,
+    stopped: bool,
+}
+
+impl HookedProcess {
+    fn on_stopped(&mut self) {
+        if !self.stopped {
+            self.stopped = true;
+            self.hooks.run_post_stop();
+        }
+    }
+}
+
+impl PlaybackProcess for HookedProcess {
+    fn id(&self) -> u32 {
+        self.process.id()
+    }
+
+    fn try_wait(&mut self) -> Result<Option<ExitStatus>, std::io::Error> {
+        let exit_status = self.process.try_wait()?;
+        if exit_status.is_some() {
+            self.on_stopped();
+        }
+        Ok(exit_status)
+    }
+
+    fn kill(&mut self) -> Result<(), std::io::Error> {
+        self.process.kill()?;
+        self.on_stopped();
+        Ok(())
+    }
+
+    fn set_paused(&mut self, paused: bool) -> Result<(), std::io::Error> {
+        self.process.set_paused(paused)
+    }
+
+    fn seek(&mut self, target: SeekTarget) -> Result<(), std::io::Error> {
+        self.process.seek(target)
+    }
+
+    fn playlist_next(&mut self) -> Result<(), std::io::Error> {
+        self.process.playlist_next()
+    }
+
+    fn playlist_prev(&mut self) -> Result<(), std::io::Error> {
+        self.process.playlist_prev()
+    }
+
+    fn set_volume_db(&mut self, volume_db: f64) -> Result<(), std::io::Error> {
+        self.process.set_volume_db(volume_db)
+    }
+
+    fn position_query(&self) -> Option<PositionQuery> {
+        self.process.position_query()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn run_hook_test() {
+        assert!(run_hook("true").is_ok());
+        assert_eq!(
+            run_hook("exit 3").unwrap_err().to_string(),
+            "exit status: 3"
+        );
+    }
+
+    #[test]
+    fn profile_test() {
+        assert!(PlaybackHooks::new(HookProfile::None, vec![], vec![]).is_empty());
+        let hooks = PlaybackHooks::new(
+            HookProfile::Raspotify,
+            vec!["echo pre".to_string()],
+            vec!["echo post".to_string()],
+        );
+        assert_eq!(hooks.pre_play.len(), 3);
+        assert_eq!(hooks.pre_play[2], "echo pre");
+        assert_eq!(hooks.post_stop, ["echo post"]);
+    }
+
+    #[test]
+    fn hooks_run_in_background_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let gate = dir.path().join("gate");
+        let marker = dir.path().join("marker");
+        // The first hook can't finish before the gate is opened below, so getting there at all
+        // means the hooks don't block.
+        let hooks = PlaybackHooks::new(
+            HookProfile::None,
+            vec![
+                format!("while [ ! -e {} ]; do sleep 0.01; done", gate.display()),
+                format!("touch {}", marker.display()),
+            ],
+            vec![],
+        );
+        let handle = hooks.run_pre_play().unwrap();
+        assert!(!marker.exists());
+        std::fs::write(&gate, b"").unwrap();
+        handle.join().unwrap();
+        assert!(marker.exists());
+        assert!(hooks.run_post_stop().is_none());
+    }
+}
@@ -1,11 +1,16 @@
-use crate::autogrzybke::{Autogrzybke, AutogrzybkeRequest};
+use crate::autogrzybke::{Autogrzybke, AutogrzybkeRequest, PlaylistPreview};
 use crate::benny::Benny;
 use crate::http_request_handler::RequestBodyError::NameNotFound;
-use crate::player::Player;
+use crate::icy_metadata::IcyMetadata;
+use crate::playback_backend::SeekTarget;
+use crate::player::{ContentSource, Player, QueueEntry};
 use crate::resource_catalogue::ResourceCatalogue;
 use crate::schedule;
 use crate::schedule::Scheduler;
-use crate::volume_controller::VolumeController;
+use crate::stations::{Station, StationRegistry};
+use crate::status::Status;
+use crate::stream_resolver::StreamResolver;
+use crate::volume_controller::{FadeOut, VolumeController, VolumeStatus};
 use anyhow::{anyhow, Context};
 use chrono::{Duration, NaiveDateTime};
 use http::{Method, Request, Response, StatusCode};
@@ -15,32 +20,119 @@ use log::{error, info};
 use serde::de::DeserializeOwned;
 use std::collections::HashMap;
 use std::convert::Infallible;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use url_encoded_data::UrlEncodedData;
 
-pub async fn handle_request(
-    request: Request<hyper::body::Incoming>,
-    player: Arc<Player>,
-    volume_controller: Arc<VolumeController>,
-    autogrzybke: Arc<Autogrzybke>,
-    scheduler: Arc<Scheduler>,
-    benny: Arc<Benny>,
-    resources_catalogue: Arc<ResourceCatalogue>,
+/// Everything the request handler talks to.
+pub struct Services {
+    pub player: Arc<Player>,
+    pub volume_controller: Arc<VolumeController>,
+    pub autogrzybke: Arc<Autogrzybke>,
+    pub scheduler: Arc<Scheduler>,
+    pub benny: Arc<Benny>,
+    pub resources_catalogue: Arc<ResourceCatalogue>,
+    pub stream_resolver: Arc<StreamResolver>,
+    pub icy_metadata: Arc<IcyMetadata>,
+    pub stations: Arc<StationRegistry>,
+}
+
+/// Generic over the body so tests can send requests without a connection.
+pub async fn handle_request<B: RequestBody>(
+    request: Request<B>,
+    services: Arc<Services>,
+    remote_addr: SocketAddr,
 ) -> Result<Response<BoxBody<Bytes, Infallible>>, Infallible> {
+    // Volume changes are recorded with it.
+    let client = remote_addr.ip().to_string();
+    let Services {
+        player,
+        volume_controller,
+        autogrzybke,
+        scheduler,
+        benny,
+        resources_catalogue,
+        stream_resolver,
+        icy_metadata,
+        stations,
+    } = services.as_ref();
     match (request.method(), request.uri().path()) {
-        (&Method::GET, "/") => Ok(respond_with_root(&resources_catalogue)),
-        (&Method::POST, "/pause") => match player.pause() {
+        (&Method::GET, "/") => Ok(respond_with_root(
+            resources_catalogue,
+            icy_metadata,
+            stations,
+            volume_controller,
+        )),
+        (&Method::POST, "/pause") => {
+            let fade_out = match player.is_playing() {
+                true => volume_controller
+                    .fade_out()
+                    .await
+                    .inspect_err(|e| error!("Failed to fade out: {e:#}"))
+                    .unwrap_or(FadeOut::Skipped),
+                false => FadeOut::Skipped,
+            };
+            let result = match fade_out {
+                FadeOut::Cancelled => {
+                    info!("Fade out cancelled by a newer request, not pausing");
+                    Ok(())
+                }
+                FadeOut::Faded(volume) => player
+                    .pause()
+                    .map_err(|e| anyhow!(e))
+                    .and_then(|_| volume_controller.restore_after_fade_out(volume)),
+                FadeOut::Skipped => player.pause().map_err(|e| anyhow!(e)),
+            };
+            match result {
+                Ok(_) => Ok(respond_ok()),
+                Err(err) => Ok(report_internal_server_error::<&dyn std::error::Error>(
+                    err.as_ref(),
+                )),
+            }
+        }
+        (&Method::POST, "/resume") => match player.resume() {
             Ok(_) => Ok(respond_ok()),
             Err(err) => Ok(report_internal_server_error(err)),
         },
-        (&Method::POST, "/play") => {
+        (&Method::POST, "/seek") => {
             match collect_request_body(request)
                 .await
-                .and_then(|b| get_value_from_form_body(b, "stream_url"))
-                .and_then(|url| {
+                .and_then(|b| get_value_from_form_body(b, "position"))
+                .and_then(|position| {
+                    position
+                        .parse::<SeekTarget>()
+                        .map_err(|e| anyhow!(e).context("Parse position as seconds"))
+                })
+                .and_then(|target| player.seek(target).map_err(|e| anyhow!(e)))
+            {
+                Ok(_) => Ok(respond_ok()),
+                Err(err) => Ok(report_internal_server_error::<&dyn std::error::Error>(
+                    err.as_ref(),
+                )),
+            }
+        }
+        (&Method::POST, "/next") => match player.playlist_next() {
+            Ok(_) => Ok(respond_ok()),
+            Err(err) => Ok(report_internal_server_error(err)),
+        },
+        (&Method::POST, "/prev") => match player.playlist_prev() {
+            Ok(_) => Ok(respond_ok()),
+            Err(err) => Ok(report_internal_server_error(err)),
+        },
+        (&Method::POST, "/play") => {
+            match collect_stream_url(request, stream_resolver, stations)
+                .await
+                .and_then(|(link, url, station)| {
+                    let already_playing = player.current_stream_url().as_ref() == Some(&url);
                     player
-                        .play(url, chrono::Duration::seconds(0))
-                        .map_err(|e| anyhow!(e))
+                        .play(link, url, chrono::Duration::seconds(0))
+                        .map_err(|e| anyhow!(e))?;
+                    let volume = station.and_then(|s| s.default_volume_percent);
+                    match (already_playing, volume) {
+                        (false, volume) => volume_controller.fade_in(volume, &client),
+                        (true, Some(volume)) => volume_controller.set_volume(volume, &client),
+                        (true, None) => Ok(()),
+                    }
                 }) {
                 Ok(_) => Ok(respond_ok()),
                 Err(err) => Ok(report_internal_server_error::<&dyn std::error::Error>(
@@ -52,8 +144,7 @@ pub async fn handle_request(
             match collect_request_body(request)
                 .await
                 .and_then(|b| get_value_from_form_body(b, "playlist"))
-                .and_then(|s| Ok(s.trim().to_string()))
-                .and_then(|missing| Ok(missing.split("\r\n").map(|slice| slice.into()).collect()))
+                .map(parse_playlist)
                 .and_then(|playlist| player.play_local_playlist(playlist).map_err(|e| anyhow!(e)))
             {
                 Ok(_) => Ok(respond_ok()),
@@ -62,6 +153,96 @@ pub async fn handle_request(
                 )),
             }
         }
+        (&Method::GET, "/status") => {
+            match serde_json::to_string(&Status::collect(
+                player,
+                volume_controller,
+                scheduler,
+                icy_metadata,
+            )) {
+                Ok(json) => Ok(respond_with_json(json)),
+                Err(err) => Ok(report_internal_server_error(err)),
+            }
+        }
+        (&Method::GET, "/queue") => match serde_json::to_string(&player.get_queue()) {
+            Ok(json) => Ok(respond_with_json(json)),
+            Err(err) => Ok(report_internal_server_error(err)),
+        },
+        (&Method::POST, "/queue/add") => {
+            match collect_stream_url(request, stream_resolver, stations)
+                .await
+                .and_then(|(link, url, _)| player.enqueue(link, url).map_err(|e| anyhow!(e)))
+            {
+                Ok(_) => Ok(respond_ok()),
+                Err(err) => Ok(report_internal_server_error::<&dyn std::error::Error>(
+                    err.as_ref(),
+                )),
+            }
+        }
+        (&Method::POST, "/queue/addserverfiles") => {
+            match collect_request_body(request)
+                .await
+                .and_then(|b| get_value_from_form_body(b, "playlist"))
+                .map(parse_playlist)
+                .and_then(|playlist| {
+                    player
+                        .enqueue_local_playlist(playlist)
+                        .map_err(|e| anyhow!(e))
+                }) {
+                Ok(_) => Ok(respond_ok()),
+                Err(err) => Ok(report_internal_server_error::<&dyn std::error::Error>(
+                    err.as_ref(),
+                )),
+            }
+        }
+        (&Method::POST, "/queue/remove") => {
+            match collect_request_body(request)
+                .await
+                .and_then(|b| get_value_from_form_body(b, "index"))
+                .and_then(|index| {
+                    index
+                        .parse::<usize>()
+                        .map_err(|e| anyhow!(e).context("Parse index as usize"))
+                })
+                .and_then(|index| player.dequeue(index).map_err(|e| anyhow!(e)))
+            {
+                Ok(_) => Ok(respond_ok()),
+                Err(err) => Ok(report_internal_server_error::<&dyn std::error::Error>(
+                    err.as_ref(),
+                )),
+            }
+        }
+        (&Method::POST, "/queue/move") => {
+            match collect_request_body(request)
+                .await
+                .and_then(get_values_from_form_body)
+                .and_then(|params| {
+                    let from: usize = params
+                        .get("from")
+                        .ok_or(anyhow!(NameNotFound("from".to_string())))?
+                        .parse()
+                        .context("Parse from as usize")?;
+                    let to: usize = params
+                        .get("to")
+                        .ok_or(anyhow!(NameNotFound("to".to_string())))?
+                        .parse()
+                        .context("Parse to as usize")?;
+                    player.reorder_queue(from, to).map_err(|e| anyhow!(e))
+                }) {
+                Ok(_) => Ok(respond_ok()),
+                Err(err) => Ok(report_internal_server_error::<&dyn std::error::Error>(
+                    err.as_ref(),
+                )),
+            }
+        }
+        (&Method::POST, "/queue/skip") => match player.skip() {
+            Ok(_) => Ok(respond_ok()),
+            Err(err) => Ok(report_internal_server_error(err)),
+        },
+        (&Method::POST, "/queue/clear") => {
+            player.clear_queue();
+            Ok(respond_ok())
+        }
         (&Method::GET, "/listserverfiles") => Ok(respond_with_html(
             resources_catalogue.get_joned_list_of_files().to_string(),
         )),
@@ -76,7 +257,7 @@ pub async fn handle_request(
                     vol.parse::<i32>()
                         .map_err(|e| anyhow!(e).context("Parse volume_delta as int"))
                 })
-                .and_then(|vol| volume_controller.change_volume(vol))
+                .and_then(|vol| volume_controller.change_volume(vol, &client))
             {
                 Ok(_) => Ok(respond_ok()),
                 Err(err) => Ok(report_internal_server_error::<&dyn std::error::Error>(
@@ -84,16 +265,132 @@ pub async fn handle_request(
                 )),
             }
         }
+        (&Method::GET, "/volume") => match volume_controller
+            .get_status()
+            .and_then(|status| serde_json::to_string(&status).map_err(|e| anyhow!(e)))
+        {
+            Ok(json) => Ok(respond_with_json(json)),
+            Err(err) => Ok(report_internal_server_error::<&dyn std::error::Error>(
+                err.as_ref(),
+            )),
+        },
+        (&Method::GET, "/volume/history") => {
+            match serde_json::to_string(&volume_controller.history()) {
+                Ok(json) => Ok(respond_with_json(json)),
+                Err(err) => Ok(report_internal_server_error(err)),
+            }
+        }
+        (&Method::PUT, "/volume") => {
+            let volume_req: VolumeRequest = match collect_request_body(request)
+                .await
+                .and_then(|b| serde_json::from_slice(&b).context("Parse volume request"))
+            {
+                Ok(volume_req) => volume_req,
+                Err(err) => {
+                    return Ok(report_internal_server_error::<&dyn std::error::Error>(
+                        err.as_ref(),
+                    ))
+                }
+            };
+            // Negative, NaN and huge durations can't be ramped over.
+            let ramp = match volume_req
+                .ramp_secs
+                .map(std::time::Duration::try_from_secs_f64)
+                .transpose()
+            {
+                Ok(ramp) => ramp,
+                Err(err) => return Ok(report_bad_request(err)),
+            };
+            match apply_volume_request(volume_controller, &volume_req, ramp, &client)
+                .and_then(|status| serde_json::to_string(&status).map_err(|e| anyhow!(e)))
+            {
+                Ok(json) => Ok(respond_with_json(json)),
+                Err(err) => Ok(report_internal_server_error::<&dyn std::error::Error>(
+                    err.as_ref(),
+                )),
+            }
+        }
+        (&Method::POST, "/volume") => {
+            match collect_request_body(request)
+                .await
+                .and_then(|b| get_value_from_form_body(b, "volume_percent"))
+                .and_then(|vol| {
+                    vol.parse::<i32>()
+                        .map_err(|e| anyhow!(e).context("Parse volume_percent as int"))
+                })
+                .and_then(|vol| volume_controller.set_volume(vol, &client))
+            {
+                Ok(_) => Ok(respond_ok()),
+                Err(err) => Ok(report_internal_server_error::<&dyn std::error::Error>(
+                    err.as_ref(),
+                )),
+            }
+        }
+        (&Method::POST, "/volume/mute") => match volume_controller.mute(&client) {
+            Ok(_) => Ok(respond_ok()),
+            Err(err) => Ok(report_internal_server_error::<&dyn std::error::Error>(
+                err.as_ref(),
+            )),
+        },
+        (&Method::POST, "/volume/unmute") => match volume_controller.unmute(&client) {
+            Ok(_) => Ok(respond_ok()),
+            Err(err) => Ok(report_internal_server_error::<&dyn std::error::Error>(
+                err.as_ref(),
+            )),
+        },
+        (&Method::POST, "/volume/toggle_mute") => match volume_controller.toggle_mute(&client) {
+            Ok(_) => Ok(respond_ok()),
+            Err(err) => Ok(report_internal_server_error::<&dyn std::error::Error>(
+                err.as_ref(),
+            )),
+        },
         (&Method::GET, "/autogrzybke") => {
             Ok(respond_with_autogrzybke(autogrzybke.get_last_missing()))
         }
-        (&Method::POST, "/autogrzybke") => {
+        (&Method::GET, "/autogrzybke/nicks") => {
+            match serde_json::to_string(&autogrzybke.get_nick_resolutions()) {
+                Ok(json) => Ok(respond_with_json(json)),
+                Err(err) => Ok(report_internal_server_error(err)),
+            }
+        }
+        (&Method::POST, "/autogrzybke/preview") => {
             match collect_request_body(request)
                 .await
-                .and_then(|b| parse_urlencoded_body(b))
-                .and_then(|autogrzybke_req: AutogrzybkeRequest| {
-                    Ok(autogrzybke.generate_playlist(autogrzybke_req))
+                .and_then(parse_urlencoded_body)
+                .map(|autogrzybke_req: AutogrzybkeRequest| {
+                    autogrzybke.preview_playlist(&autogrzybke_req)
                 })
+                .and_then(|preview| serde_json::to_string(&preview).map_err(|e| anyhow!(e)))
+            {
+                Ok(json) => Ok(respond_with_json(json)),
+                Err(err) => Ok(report_internal_server_error::<&dyn std::error::Error>(
+                    err.as_ref(),
+                )),
+            }
+        }
+        (&Method::POST, "/autogrzybke/preview/html") => {
+            match collect_request_body(request)
+                .await
+                .and_then(parse_urlencoded_body)
+            {
+                Ok(autogrzybke_req) => Ok(respond_with_autogrzybke_preview(
+                    autogrzybke.preview_playlist(&autogrzybke_req),
+                    &autogrzybke_req,
+                )),
+                Err(err) => Ok(report_internal_server_error::<&dyn std::error::Error>(
+                    err.as_ref(),
+                )),
+            }
+        }
+        (&Method::POST, "/autogrzybke") => {
+            let playlist = match collect_request_body(request)
+                .await
+                .and_then(parse_urlencoded_body::<AutogrzybkeRequest>)
+            {
+                Ok(autogrzybke_req) => Ok(autogrzybke.generate_playlist(autogrzybke_req).await),
+                Err(err) => Err(err),
+            };
+            match playlist
                 .inspect(|playlist| {
                     info!("Generated playlist:\n{}", playlist.join("\n"));
                 })
@@ -101,7 +398,9 @@ pub async fn handle_request(
                     if playlist.is_empty() {
                         anyhow::bail!("Empty playlist. Resources not available?")
                     } else {
-                        player.play_local_playlist(playlist).map_err(|e| anyhow!(e))
+                        player
+                            .interrupt_with_local_playlist(playlist, ContentSource::Autogrzybke)
+                            .map_err(|e| anyhow!(e))
                     }
                 }) {
                 Ok(_) => Ok(respond_ok()),
@@ -110,7 +409,7 @@ pub async fn handle_request(
                 )),
             }
         }
-        (&Method::GET, "/jukebox") => Ok(respond_with_jukebox()),
+        (&Method::GET, "/jukebox") => Ok(respond_with_jukebox(player.get_queue())),
         (&Method::GET, "/autohypys") => Ok(respond_with_schedule(
             scheduler.get_serialized_schedule(),
             Scheduler::get_default_schedule_end_string(),
@@ -133,7 +432,7 @@ pub async fn handle_request(
         (&Method::POST, "/autohypys/generate_schedule") => {
             match collect_request_body(request)
                 .await
-                .and_then(|b| get_values_from_form_body(b))
+                .and_then(get_values_from_form_body)
                 .and_then(|params| {
                     let period: Duration = Duration::minutes(
                         params
@@ -144,7 +443,7 @@ pub async fn handle_request(
                             .parse()?,
                     );
                     let end_date_time = NaiveDateTime::parse_from_str(
-                        &params
+                        params
                             .get("generate_schedule_end_datetime_local")
                             .ok_or(anyhow!(NameNotFound(
                                 "generate_schedule_end_datetime_local".to_string()
@@ -183,10 +482,64 @@ pub async fn handle_request(
                 err.as_ref(),
             )),
         },
+        (&Method::GET, "/stations") => match serde_json::to_string(&stations.list()) {
+            Ok(json) => Ok(respond_with_json(json)),
+            Err(err) => Ok(report_internal_server_error(err)),
+        },
+        (&Method::GET, "/stations/manage") => Ok(respond_with_stations(stations.list())),
+        (&Method::POST, "/stations/add") => {
+            match collect_request_body(request)
+                .await
+                .and_then(get_values_from_form_body)
+                .and_then(station_from_form)
+                .and_then(|station| stations.add(station))
+            {
+                Ok(_) => Ok(respond_with_stations(stations.list())),
+                Err(err) => Ok(report_internal_server_error::<&dyn std::error::Error>(
+                    err.as_ref(),
+                )),
+            }
+        }
+        (&Method::POST, "/stations/update") => {
+            match collect_request_body(request)
+                .await
+                .and_then(get_values_from_form_body)
+                .and_then(station_from_form)
+                .and_then(|station| stations.update(station))
+            {
+                Ok(_) => Ok(respond_with_stations(stations.list())),
+                Err(err) => Ok(report_internal_server_error::<&dyn std::error::Error>(
+                    err.as_ref(),
+                )),
+            }
+        }
+        (&Method::POST, "/stations/remove") => {
+            match collect_request_body(request)
+                .await
+                .and_then(|b| get_value_from_form_body(b, "name"))
+                .and_then(|name| stations.remove(&name))
+            {
+                Ok(_) => Ok(respond_with_stations(stations.list())),
+                Err(err) => Ok(report_internal_server_error::<&dyn std::error::Error>(
+                    err.as_ref(),
+                )),
+            }
+        }
         _ => Ok(respond_not_found()),
     }
 }
 
+fn report_bad_request<E>(error: E) -> Response<BoxBody<Bytes, Infallible>>
+where
+    E: std::error::Error,
+{
+    info!("Bad request: {error}");
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(Full::new(format!("{error}").into()).boxed())
+        .unwrap()
+}
+
 fn report_internal_server_error<E>(error: E) -> Response<BoxBody<Bytes, Infallible>>
 where
     E: std::error::Error,
@@ -206,8 +559,45 @@ fn respond_with_html(html: String) -> Response<BoxBody<Bytes, Infallible>> {
         .unwrap()
 }
 
+fn respond_with_json(json: String) -> Response<BoxBody<Bytes, Infallible>> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Cache-Control", "no-store")
+        .header("Content-Type", "application/json")
+        .body(Full::new(Bytes::from(json)).boxed())
+        .unwrap()
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Puts the values in place of their placeholders in one pass, so a station name or a stream
+/// title containing another placeholder is left as it is.
+fn fill_placeholders(html: &str, values: &[(&str, &str)]) -> String {
+    let mut filled = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some((at, placeholder, value)) = values
+        .iter()
+        .filter_map(|(placeholder, value)| Some((rest.find(placeholder)?, *placeholder, *value)))
+        .min_by_key(|(at, placeholder, _)| (*at, std::cmp::Reverse(placeholder.len())))
+    {
+        filled.push_str(&rest[..at]);
+        filled.push_str(value);
+        rest = &rest[at + placeholder.len()..];
+    }
+    filled.push_str(rest);
+    filled
+}
+
 fn respond_with_root(
     resources_catalogue: &Arc<ResourceCatalogue>,
+    icy_metadata: &IcyMetadata,
+    stations: &StationRegistry,
+    volume_controller: &VolumeController,
 ) -> Response<BoxBody<Bytes, Infallible>> {
     let html = match resources_catalogue.get_joned_list_of_files().is_empty() {
         true => include_str!("fosiaudio_chilli.html").to_string(),
@@ -216,7 +606,58 @@ fn respond_with_root(
                 + include_str!("lanparty_features.html")
         }
     };
-    respond_with_html(html)
+    let now_playing = icy_metadata
+        .current_title()
+        .map(|title| escape_html(&title))
+        .unwrap_or_else(|| "-".to_string());
+    let title_history = icy_metadata
+        .history()
+        .iter()
+        .map(|change| {
+            format!(
+                "<li>{} {}</li>",
+                change.at.format("%H:%M"),
+                escape_html(&change.title)
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    let station_buttons = stations
+        .list()
+        .iter()
+        .map(|station| {
+            let name = escape_html(&station.name);
+            let logo = station
+                .logo
+                .as_ref()
+                .map(|logo| {
+                    format!(
+                        r#"<img src="{}" alt="" style="height:1em;"> "#,
+                        escape_html(logo)
+                    )
+                })
+                .unwrap_or_default();
+            format!(
+                r#"<form action="/play" method="post">
+    <button style="font-size:8em;" name="station" type="submit" value="{name}">{logo}{name}</button>
+</form>"#
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    let volume = volume_controller
+        .get_volume()
+        .inspect_err(|e| error!("Root page without volume: {e:#}"))
+        .unwrap_or(0);
+    respond_with_html(fill_placeholders(
+        &html,
+        &[
+            ("STATION_BUTTONS", &station_buttons),
+            ("CURRENT_VOLUME", &volume.to_string()),
+            ("NOW_PLAYING", &now_playing),
+            ("TITLE_HISTORY", &title_history),
+        ],
+    ))
 }
 
 fn respond_with_autogrzybke(missing: Vec<String>) -> Response<BoxBody<Bytes, Infallible>> {
@@ -225,8 +666,79 @@ fn respond_with_autogrzybke(missing: Vec<String>) -> Response<BoxBody<Bytes, Inf
     respond_with_html(html)
 }
 
-fn respond_with_jukebox() -> Response<BoxBody<Bytes, Infallible>> {
+fn respond_with_autogrzybke_preview(
+    preview: PlaylistPreview,
+    req: &AutogrzybkeRequest,
+) -> Response<BoxBody<Bytes, Infallible>> {
+    let html = include_str!("autogrzybke_preview.html").to_string();
+    let nicks = preview
+        .nicks
+        .iter()
+        .map(|nick| {
+            format!(
+                "<tr><td>{}</td><td>{}</td><td>{:?}</td><td>{}</td><td>{}</td></tr>",
+                escape_html(&nick.nick),
+                escape_html(&nick.sample_key),
+                nick.source,
+                nick.missing_streak,
+                escape_html(nick.level.as_deref().unwrap_or("-"))
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    let entries = preview
+        .entries
+        .iter()
+        .map(|entry| {
+            format!(
+                "<li>{} ({:?}): {}</li>",
+                escape_html(&entry.sample_key),
+                entry.source,
+                escape_html(entry.file.as_deref().unwrap_or("-"))
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    respond_with_html(fill_placeholders(
+        &html,
+        &[
+            ("PREVIEW_NICKS", &nicks),
+            ("PREVIEW_ENTRIES", &entries),
+            ("PREVIEW_MISSING", &escape_html(&req.missing.join("\n"))),
+        ],
+    ))
+}
+
+fn respond_with_jukebox(queue: Vec<QueueEntry>) -> Response<BoxBody<Bytes, Infallible>> {
     let html = include_str!("jukebox.html").to_string();
+    let queue_content = queue
+        .iter()
+        .map(|entry| format!("<li>{}</li>", escape_html(&entry.entries.join(", "))))
+        .collect::<Vec<String>>()
+        .join("\n");
+    let html = html.replace("QUEUE_CONTENT", queue_content.as_str());
+    respond_with_html(html)
+}
+
+fn respond_with_stations(stations: Vec<Station>) -> Response<BoxBody<Bytes, Infallible>> {
+    let html = include_str!("stations.html").to_string();
+    let stations_content = stations
+        .iter()
+        .map(|station| {
+            format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                escape_html(&station.name),
+                escape_html(&station.url),
+                escape_html(station.logo.as_deref().unwrap_or_default()),
+                station
+                    .default_volume_percent
+                    .map(|v| format!("{v}%"))
+                    .unwrap_or_default()
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    let html = html.replace("STATIONS_CONTENT", stations_content.as_str());
     respond_with_html(html)
 }
 
@@ -237,9 +749,13 @@ fn respond_with_schedule(
     match schedule_text {
         Ok(text) => {
             let html = include_str!("autohypys.html").to_string();
-            let html = html.replace("SCHEDULE_CONTENT", text.as_str());
-            let html = html.replace("SCHEDULE_END_DEFAULT", schedule_end_default.as_str());
-            respond_with_html(html)
+            respond_with_html(fill_placeholders(
+                &html,
+                &[
+                    ("SCHEDULE_CONTENT", &text),
+                    ("SCHEDULE_END_DEFAULT", &schedule_end_default),
+                ],
+            ))
         }
         Err(e) => respond_with_html(format!("{e}")),
     }
@@ -248,18 +764,26 @@ fn respond_with_schedule(
 fn respond_ok() -> Response<BoxBody<Bytes, Infallible>> {
     let mut response = Response::new(Empty::<Bytes>::new().boxed());
     *response.status_mut() = StatusCode::NO_CONTENT;
-    return response.into();
+    response
 }
 
 fn respond_not_found() -> Response<BoxBody<Bytes, Infallible>> {
     let mut response = Response::new(Empty::<Bytes>::new().boxed());
     *response.status_mut() = StatusCode::NOT_FOUND;
-    return response.into();
+    response
+}
+
+/// `hyper::body::Incoming` when serving, anything collectable in tests.
+pub trait RequestBody:
+    hyper::body::Body<Data = Bytes, Error: std::error::Error + Send + Sync + 'static>
+{
+}
+impl<B> RequestBody for B where
+    B: hyper::body::Body<Data = Bytes, Error: std::error::Error + Send + Sync + 'static>
+{
 }
 
-async fn collect_request_body(
-    request: Request<hyper::body::Incoming>,
-) -> Result<Bytes, anyhow::Error> {
+async fn collect_request_body<B: RequestBody>(request: Request<B>) -> Result<Bytes, anyhow::Error> {
     let bytes = request
         .into_body()
         .collect()
@@ -269,6 +793,87 @@ async fn collect_request_body(
     Ok(bytes)
 }
 
+/// Reads `stream_url`, or the name of a `station`, from the form and resolves it to something
+/// the player can open. Returns the link, what it resolved to and the station.
+async fn collect_stream_url<B: RequestBody>(
+    request: Request<B>,
+    stream_resolver: &StreamResolver,
+    stations: &StationRegistry,
+) -> Result<(String, String, Option<Station>), anyhow::Error> {
+    let mut params = get_values_from_form_body(collect_request_body(request).await?)?;
+    let (url, station) = match params.remove("station") {
+        Some(name) => {
+            let station = stations
+                .get(&name)
+                .ok_or(anyhow!("No station named {name}"))?;
+            (station.url.clone(), Some(station))
+        }
+        None => (
+            params
+                .remove("stream_url")
+                .ok_or(anyhow!(NameNotFound("stream_url".to_string())))?,
+            None,
+        ),
+    };
+    let link = url.trim().to_string();
+    let url = stream_resolver.resolve(&link).await?;
+    Ok((link, url, station))
+}
+
+fn station_from_form(mut params: HashMap<String, String>) -> Result<Station, anyhow::Error> {
+    let mut take = |name: &str| {
+        params
+            .remove(name)
+            .ok_or(anyhow!(NameNotFound(name.to_string())))
+    };
+    let name = take("name")?;
+    let url = take("url")?;
+    let logo = take("logo").ok();
+    let default_volume_percent = match take("default_volume_percent") {
+        Ok(volume) if !volume.trim().is_empty() => Some(
+            volume
+                .trim()
+                .parse()
+                .context("Parse default_volume_percent as int")?,
+        ),
+        _ => None,
+    };
+    Ok(Station {
+        name,
+        url,
+        logo,
+        default_volume_percent,
+    })
+}
+
+/// Body of `PUT /volume`, both fields optional.
+#[derive(serde::Deserialize, Debug)]
+struct VolumeRequest {
+    volume_percent: Option<i32>,
+    /// Ramp to `volume_percent` over that many seconds instead of jumping.
+    ramp_secs: Option<f64>,
+    muted: Option<bool>,
+}
+
+fn apply_volume_request(
+    volume_controller: &Arc<VolumeController>,
+    volume_req: &VolumeRequest,
+    ramp: Option<std::time::Duration>,
+    client: &str,
+) -> Result<VolumeStatus, anyhow::Error> {
+    match (volume_req.volume_percent, ramp) {
+        (Some(percent), Some(ramp)) => volume_controller.start_ramp(percent, ramp, client)?,
+        (Some(percent), None) => volume_controller.set_volume(percent, client)?,
+        (None, _) => (),
+    }
+    match volume_req.muted {
+        Some(true) => volume_controller.mute(client)?,
+        Some(false) => volume_controller.unmute(client)?,
+        None => (),
+    }
+    volume_controller.get_status()
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum RequestBodyError {
     #[error("Request body is empty. Expected: \"stream_url=<url>\"")]
@@ -284,7 +889,7 @@ fn get_values_from_form_body(body: Bytes) -> Result<HashMap<String, String>, any
         .utf8_chunks()
         .next()
         .ok_or(anyhow!(RequestBodyError::EmptyBody))
-        .and_then(|chunk| Ok(chunk.valid()))?;
+        .map(|chunk| chunk.valid())?;
     Ok(UrlEncodedData::parse_str(chunk)
         .as_map_of_single_key_to_last_occurrence_value()
         .iter()
@@ -298,11 +903,252 @@ fn get_value_from_form_body(body: Bytes, name: &str) -> Result<String, anyhow::E
         .ok_or(anyhow!(RequestBodyError::NameNotFound(name.to_string())))
 }
 
+fn parse_playlist(text: String) -> Vec<String> {
+    text.trim()
+        .split("\r\n")
+        .map(|slice| slice.into())
+        .collect()
+}
+
 fn parse_urlencoded_body<T: DeserializeOwned>(body: Bytes) -> Result<T, anyhow::Error> {
     let chunk = body
         .utf8_chunks()
         .next()
         .ok_or(anyhow!(RequestBodyError::EmptyBody))
-        .and_then(|chunk| Ok(chunk.valid()))?;
+        .map(|chunk| chunk.valid())?;
     Ok(serde_urlencoded::from_str(chunk)?)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nick_aliases::NickAliases;
+    use crate::phrase_templates::{PhraseTemplates, PHRASE_TEMPLATES_DEFAULT};
+    use crate::playback_backend::{PlaybackSource, PlaylistFile};
+    use crate::player::{InterruptionMode, ReconnectPolicy, SourceGains};
+    use crate::recording_backend::RecordingBackend;
+    use crate::volume_backend::FakeVolumeBackend;
+    use crate::volume_caps::VolumeCaps;
+    use crate::volume_controller::FadePolicy;
+
+    struct TestServices {
+        services: Arc<Services>,
+        backend: RecordingBackend,
+        _dir: tempfile::TempDir,
+    }
+
+    fn test_services(fade_policy: FadePolicy) -> TestServices {
+        let dir = tempfile::tempdir().unwrap();
+        for file in ["noise1.mp3", "kuba1.mp3"] {
+            std::fs::write(dir.path().join(file), b"").unwrap();
+        }
+        let resources = Arc::new(ResourceCatalogue::try_from_dir_path(dir.path()).unwrap());
+        let backend = RecordingBackend::default();
+        let player = Arc::new(Player::new(
+            Box::new(backend.clone()),
+            InterruptionMode::Pause,
+            ReconnectPolicy {
+                max_attempts: 0,
+                base_delay: Duration::seconds(1),
+                stable_after: Duration::seconds(60),
+            },
+            SourceGains::default(),
+            resources.clone(),
+        ));
+        let services = Arc::new(Services {
+            player: player.clone(),
+            volume_controller: Arc::new(VolumeController::new(
+                Box::new(FakeVolumeBackend::new(50)),
+                fade_policy,
+                VolumeCaps::default(),
+                None,
+                None,
+            )),
+            autogrzybke: Arc::new(Autogrzybke::new(
+                resources.clone(),
+                NickAliases::default(),
+                None,
+                PhraseTemplates::parse(PHRASE_TEMPLATES_DEFAULT).unwrap(),
+                std::time::Duration::from_secs(60 * 15),
+            )),
+            scheduler: Arc::new(Scheduler::new(player.clone(), resources.clone()).unwrap()),
+            benny: Arc::new(Benny::new(player, None)),
+            resources_catalogue: resources,
+            stream_resolver: Arc::new(StreamResolver::new().unwrap()),
+            icy_metadata: Arc::new(IcyMetadata::new().unwrap()),
+            stations: Arc::new(StationRegistry::load(&dir.path().join("stations.yaml")).unwrap()),
+        });
+        TestServices {
+            services,
+            backend,
+            _dir: dir,
+        }
+    }
+
+    async fn send(
+        services: &Arc<Services>,
+        method: Method,
+        path: &str,
+        form: &str,
+    ) -> (StatusCode, String) {
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Full::new(Bytes::from(form.to_string())))
+            .unwrap();
+        let response = handle_request(request, services.clone(), "127.0.0.1:1234".parse().unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    #[tokio::test]
+    async fn play_and_pause_test() {
+        let TestServices {
+            services, backend, ..
+        } = test_services(FadePolicy::default());
+        let (status, _) = send(
+            &services,
+            Method::POST,
+            "/play",
+            "stream_url=%2Fmusic%2Fa.mp3",
+        )
+        .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(
+            backend.recorded()[0].source,
+            PlaybackSource::Url {
+                url: "/music/a.mp3".to_string(),
+                seek_pos: Duration::zero(),
+            }
+        );
+        let (status, body) = send(&services, Method::GET, "/status", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains(r#""state":"Playing""#), "{body}");
+
+        let (status, _) = send(&services, Method::POST, "/pause", "").await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(backend.recorded()[0].killed);
+        assert!(!services.player.is_playing());
+
+        let (status, _) = send(
+            &services,
+            Method::POST,
+            "/playserverfiles",
+            "playlist=%2Fa.mp3%0D%0A%2Fb.mp3",
+        )
+        .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(
+            backend.recorded()[1].source,
+            PlaybackSource::Files(vec![PlaylistFile::from("/a.mp3"), "/b.mp3".into()])
+        );
+
+        let (status, _) = send(&services, Method::POST, "/play", "").await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        let (status, _) = send(&services, Method::GET, "/nothing", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn autogrzybke_interrupts_playback_test() {
+        let TestServices {
+            services, backend, ..
+        } = test_services(FadePolicy::default());
+        send(
+            &services,
+            Method::POST,
+            "/play",
+            "stream_url=%2Fmusic%2Fa.mp3",
+        )
+        .await;
+        let (status, _) = send(
+            &services,
+            Method::POST,
+            "/autogrzybke",
+            "missing=kuba&skip_prefix=true&skip_suffix=true",
+        )
+        .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let recorded = backend.recorded();
+        assert_eq!(recorded.len(), 2);
+        assert!(recorded[0].killed);
+        let PlaybackSource::Files(files) = &recorded[1].source else {
+            panic!("Not a playlist: {:?}", recorded[1].source);
+        };
+        assert!(files.iter().any(|file| file.path.ends_with("kuba1.mp3")));
+        assert_eq!(
+            services.player.status().interrupted.as_deref(),
+            Some("/music/a.mp3")
+        );
+    }
+
+    #[test]
+    fn fill_placeholders_test() {
+        assert_eq!(
+            fill_placeholders(
+                "<p>NOW_PLAYING</p><ol>TITLE_HISTORY</ol>NOW_PLAYING",
+                &[
+                    ("NOW_PLAYING", "TITLE_HISTORY by CURRENT_VOLUME"),
+                    ("TITLE_HISTORY", "<li>NOW_PLAYING</li>"),
+                    ("CURRENT_VOLUME", "50"),
+                ],
+            ),
+            "<p>TITLE_HISTORY by CURRENT_VOLUME</p><ol><li>NOW_PLAYING</li></ol>\
+             TITLE_HISTORY by CURRENT_VOLUME"
+        );
+        assert_eq!(
+            fill_placeholders(
+                "SCHEDULE_END_DEFAULT SCHEDULE",
+                &[("SCHEDULE", "a"), ("SCHEDULE_END_DEFAULT", "b")]
+            ),
+            "b a"
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_ramp_test() {
+        let TestServices { services, .. } = test_services(FadePolicy::default());
+        for ramp_secs in ["-1", "1e30"] {
+            let (status, _) = send(
+                &services,
+                Method::PUT,
+                "/volume",
+                &format!(r#"{{"volume_percent": 20, "ramp_secs": {ramp_secs}}}"#),
+            )
+            .await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        }
+        assert_eq!(services.volume_controller.get_volume().unwrap(), 50);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn play_during_pause_fade_out_test() {
+        let TestServices {
+            services, backend, ..
+        } = test_services(FadePolicy {
+            fade_in: std::time::Duration::from_secs(1),
+            fade_out: std::time::Duration::from_secs(1),
+        });
+        send(&services, Method::POST, "/play", "stream_url=%2Fa.mp3").await;
+        tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+
+        let pause = tokio::spawn({
+            let services = services.clone();
+            async move { send(&services, Method::POST, "/pause", "").await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        send(&services, Method::POST, "/play", "stream_url=%2Fb.mp3").await;
+        assert_eq!(pause.await.unwrap().0, StatusCode::NO_CONTENT);
+
+        let recorded = backend.recorded();
+        assert_eq!(recorded.len(), 2);
+        assert!(recorded[0].killed);
+        assert!(!recorded[1].killed);
+        assert!(services.player.is_playing());
+        tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+        assert_eq!(services.volume_controller.get_volume().unwrap(), 50);
+    }
+}
@@ -0,0 +1,244 @@
+use crate::player::Player;
+use anyhow::{anyhow, bail, Context};
+use chrono::{DateTime, Local};
+use log::*;
+use regex::Regex;
+use reqwest::Client;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+const HISTORY_LEN: usize = 20;
+const RESTART_AFTER: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct TitleChange {
+    pub title: String,
+    pub url: String,
+    pub at: DateTime<Local>,
+}
+
+#[derive(Default)]
+struct IcyState {
+    url: Option<String>,
+    reader: Option<JoinHandle<()>>,
+    reader_started_at: Option<Instant>,
+    /// The stream doesn't send ICY metadata, don't ask again.
+    unsupported: bool,
+    current_title: Option<String>,
+    history: VecDeque<TitleChange>,
+}
+
+/// Follows "now playing" titles of the radio stream the player is playing. Opens its own
+/// connection to the stream with `Icy-MetaData: 1` and reads the `StreamTitle` updates
+/// interleaved with the audio.
+pub struct IcyMetadata {
+    client: Client,
+    state: Mutex<IcyState>,
+}
+
+impl IcyMetadata {
+    pub fn new() -> Result<Self, anyhow::Error> {
+        Ok(IcyMetadata {
+            client: Client::builder()
+                .connect_timeout(Duration::from_secs(5))
+                .read_timeout(Duration::from_secs(30))
+                .build()
+                .context("Create http client")?,
+            state: Mutex::new(IcyState::default()),
+        })
+    }
+
+    pub fn current_title(&self) -> Option<String> {
+        self.state.lock().unwrap().current_title.clone()
+    }
+
+    /// Most recent first.
+    pub fn history(&self) -> Vec<TitleChange> {
+        self.state.lock().unwrap().history.iter().cloned().collect()
+    }
+
+    pub async fn run(self: Arc<Self>, player: Arc<Player>) {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        info!("Running ICY metadata reader");
+        loop {
+            interval.tick().await;
+            let url = player.current_stream_url();
+            let mut state = self.state.lock().unwrap();
+            if state.url != url {
+                if let Some(reader) = state.reader.take() {
+                    reader.abort();
+                }
+                state.url = url;
+                state.unsupported = false;
+                state.current_title = None;
+                state.reader_started_at = None;
+            }
+            let Some(url) = state.url.clone() else {
+                continue;
+            };
+            let reader_running = state.reader.as_ref().is_some_and(|r| !r.is_finished());
+            let may_start = state
+                .reader_started_at
+                .is_none_or(|started_at| started_at.elapsed() > RESTART_AFTER);
+            if !reader_running && !state.unsupported && may_start {
+                info!("Read ICY metadata of {url}");
+                let this = self.clone();
+                state.reader_started_at = Some(Instant::now());
+                state.reader = Some(tokio::task::spawn(async move {
+                    this.read_titles(&url)
+                        .await
+                        .unwrap_or_else(|e| warn!("Stopped reading ICY metadata of {url}: {e:#}"));
+                }));
+            }
+        }
+    }
+
+    async fn read_titles(&self, url: &str) -> Result<(), anyhow::Error> {
+        let mut response = self
+            .client
+            .get(url)
+            .header("Icy-MetaData", "1")
+            .send()
+            .await
+            .context(format!("GET {url}"))?;
+        if !response.status().is_success() {
+            bail!("GET {url}: HTTP {}", response.status());
+        }
+        let Some(metaint) = response
+            .headers()
+            .get("icy-metaint")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse().ok())
+        else {
+            self.state.lock().unwrap().unsupported = true;
+            return Err(anyhow!("Stream doesn't send ICY metadata"));
+        };
+        let mut parser = IcyParser::new(metaint);
+        while let Some(chunk) = response.chunk().await.context("Read stream")? {
+            for block in parser.feed(&chunk) {
+                if let Some(title) = parse_stream_title(&block) {
+                    self.record_title(url, title);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn record_title(&self, url: &str, title: String) {
+        let mut state = self.state.lock().unwrap();
+        if state.url.as_deref() != Some(url)
+            || title.is_empty()
+            || state.current_title.as_ref() == Some(&title)
+        {
+            return;
+        }
+        info!("Now playing: {title}");
+        state.current_title = Some(title.clone());
+        state.history.push_front(TitleChange {
+            title,
+            url: url.to_string(),
+            at: Local::now(),
+        });
+        state.history.truncate(HISTORY_LEN);
+    }
+}
+
+/// Splits an ICY stream into metadata blocks: `metaint` bytes of audio, one length byte
+/// counting 16 byte units, then that much metadata.
+struct IcyParser {
+    metaint: usize,
+    audio_left: usize,
+    metadata_left: Option<usize>,
+    metadata: Vec<u8>,
+}
+
+impl IcyParser {
+    fn new(metaint: usize) -> Self {
+        IcyParser {
+            metaint,
+            audio_left: metaint,
+            metadata_left: None,
+            metadata: Vec::new(),
+        }
+    }
+
+    fn feed(&mut self, mut bytes: &[u8]) -> Vec<String> {
+        let mut blocks = Vec::new();
+        while !bytes.is_empty() {
+            if self.audio_left > 0 {
+                let skip = self.audio_left.min(bytes.len());
+                self.audio_left -= skip;
+                bytes = &bytes[skip..];
+                continue;
+            }
+            let metadata_left = match self.metadata_left {
+                Some(left) => left,
+                None => {
+                    let length = bytes[0] as usize * 16;
+                    bytes = &bytes[1..];
+                    length
+                }
+            };
+            let take = metadata_left.min(bytes.len());
+            self.metadata.extend_from_slice(&bytes[..take]);
+            bytes = &bytes[take..];
+            if take == metadata_left {
+                if !self.metadata.is_empty() {
+                    let block = String::from_utf8_lossy(&self.metadata);
+                    blocks.push(block.trim_end_matches('\0').to_string());
+                }
+                self.metadata.clear();
+                self.metadata_left = None;
+                self.audio_left = self.metaint;
+            } else {
+                self.metadata_left = Some(metadata_left - take);
+            }
+        }
+        blocks
+    }
+}
+
+fn parse_stream_title(block: &str) -> Option<String> {
+    let re = Regex::new(r"(?s)StreamTitle='(?<title>.*?)';").unwrap();
+    re.captures(block)
+        .map(|caps| caps["title"].trim().to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn icy_block(metadata: &str) -> Vec<u8> {
+        let mut bytes = metadata.as_bytes().to_vec();
+        bytes.resize(bytes.len().div_ceil(16) * 16, 0);
+        let mut block = vec![(bytes.len() / 16) as u8];
+        block.extend(bytes);
+        block
+    }
+
+    #[test]
+    fn icy_parser_test() {
+        let mut stream = vec![1u8; 8];
+        stream.extend(icy_block(
+            "StreamTitle='Artist - It's a song';StreamUrl='';",
+        ));
+        stream.extend(vec![2u8; 8]);
+        stream.extend(icy_block(""));
+        stream.extend(vec![3u8; 8]);
+        stream.extend(icy_block("StreamTitle='Next';"));
+
+        // Feed in awkward pieces, to cross block boundaries.
+        let mut parser = IcyParser::new(8);
+        let blocks: Vec<String> = stream.chunks(5).flat_map(|c| parser.feed(c)).collect();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(
+            parse_stream_title(&blocks[0]).unwrap(),
+            "Artist - It's a song"
+        );
+        assert_eq!(parse_stream_title(&blocks[1]).unwrap(), "Next");
+        assert_eq!(parse_stream_title("StreamUrl='x';"), None);
+    }
+}
@@ -0,0 +1,134 @@
+use crate::atomic_write::write_atomically;
+use anyhow::{anyhow, Context};
+use log::*;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::SystemTime;
+
+/// ffmpeg couldn't be started, no file can be measured.
+#[derive(thiserror::Error, Debug)]
+#[error("Can't run {0}")]
+pub struct AnalyzerUnavailable(String);
+
+/// Integrated loudness of a file, valid as long as the file keeps its size and mtime.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct CachedLoudness {
+    integrated_lufs: f64,
+    size: u64,
+    modified: SystemTime,
+}
+
+/// Measures EBU R128 integrated loudness with ffmpeg's `ebur128` filter and remembers the
+/// results in a json file, so only new or changed files get analyzed again.
+pub struct LoudnessAnalyzer {
+    ffmpeg_path: String,
+    cache_path: PathBuf,
+    cache: HashMap<PathBuf, CachedLoudness>,
+}
+
+impl LoudnessAnalyzer {
+    pub fn new(ffmpeg_path: &str, cache_path: &Path) -> Self {
+        let cache = match std::fs::read_to_string(cache_path) {
+            Ok(text) => serde_json::from_str(&text)
+                .inspect_err(|e| warn!("Ignoring broken {}: {e}", cache_path.display()))
+                .unwrap_or_default(),
+            Err(e) => {
+                info!("No loudness cache at {}: {e}", cache_path.display());
+                HashMap::new()
+            }
+        };
+        LoudnessAnalyzer {
+            ffmpeg_path: ffmpeg_path.to_string(),
+            cache_path: cache_path.to_path_buf(),
+            cache,
+        }
+    }
+
+    /// Integrated loudness in LUFS, measured unless cached.
+    pub fn integrated_loudness(&mut self, file: &Path) -> Result<f64, anyhow::Error> {
+        let metadata = std::fs::metadata(file).context(format!("Stat {}", file.display()))?;
+        let size = metadata.len();
+        let modified = metadata.modified()?;
+        if let Some(cached) = self.cache.get(file) {
+            if cached.size == size && cached.modified == modified {
+                return Ok(cached.integrated_lufs);
+            }
+        }
+        let integrated_lufs = self.measure(file)?;
+        info!("{}: {integrated_lufs} LUFS", file.display());
+        self.cache.insert(
+            file.to_path_buf(),
+            CachedLoudness {
+                integrated_lufs,
+                size,
+                modified,
+            },
+        );
+        Ok(integrated_lufs)
+    }
+
+    fn measure(&self, file: &Path) -> Result<f64, anyhow::Error> {
+        let output = Command::new(&self.ffmpeg_path)
+            .args(["-hide_banner", "-nostats", "-i"])
+            .arg(file)
+            .args(["-af", "ebur128", "-f", "null", "-"])
+            .output()
+            .map_err(|e| anyhow!(e).context(AnalyzerUnavailable(self.ffmpeg_path.clone())))?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !output.status.success() {
+            return Err(anyhow!(
+                "{} failed on {} with {}: {}",
+                self.ffmpeg_path,
+                file.display(),
+                output.status,
+                stderr.lines().last().unwrap_or_default()
+            ));
+        }
+        parse_integrated_loudness(&stderr)
+    }
+
+    pub fn save(&self) -> Result<(), anyhow::Error> {
+        write_atomically(
+            &self.cache_path,
+            serde_json::to_string(&self.cache)?.as_bytes(),
+        )
+    }
+}
+
+/// The `I:` line of the summary ebur128 prints at the end.
+fn parse_integrated_loudness(stderr: &str) -> Result<f64, anyhow::Error> {
+    let summary = stderr
+        .rsplit_once("Summary:")
+        .map(|(_, summary)| summary)
+        .ok_or(anyhow!("No ebur128 summary in ffmpeg output"))?;
+    let re = Regex::new(r"I:\s+(?<lufs>-?\d+(\.\d+)?) LUFS")?;
+    let caps = re.captures(summary).ok_or(anyhow!(
+        "Unable to parse integrated loudness from: {summary:?}"
+    ))?;
+    Ok(caps["lufs"].parse()?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_integrated_loudness_test() {
+        let stderr =
+            "[Parsed_ebur128_0 @ 0x1] t: 0.4 TARGET:-23 LUFS M: -20.1 S:-120.7 I: -20.1 LUFS
+[Parsed_ebur128_0 @ 0x1] Summary:
+
+  Integrated loudness:
+    I:         -17.3 LUFS
+    Threshold: -27.6 LUFS
+
+  Loudness range:
+    LRA:         4.2 LU
+";
+        assert_eq!(parse_integrated_loudness(stderr).unwrap(), -17.3);
+        assert!(parse_integrated_loudness("I: -20.1 LUFS").is_err());
+    }
+}
@@ -1,26 +1,62 @@
+#[cfg(feature = "alsa")]
+mod alsa_mixer;
+mod atomic_write;
 mod autogrzybke;
 mod benny;
+mod hooks;
 mod http_request_handler;
+mod icy_metadata;
+mod loudness;
+mod mpv_ipc;
+mod nick_aliases;
+mod phrase_templates;
+mod playback_backend;
 mod player;
+#[cfg(test)]
+mod recording_backend;
 mod resource_catalogue;
+mod saved_volume;
 mod schedule;
+mod stations;
+mod status;
+mod stream_resolver;
+mod tts;
+mod volume_backend;
+mod volume_caps;
 mod volume_controller;
+mod volume_history;
 
 use crate::autogrzybke::Autogrzybke;
 use crate::benny::Benny;
+use crate::hooks::{HookProfile, HookedBackend, PlaybackHooks};
+use crate::icy_metadata::IcyMetadata;
+use crate::loudness::LoudnessAnalyzer;
+use crate::nick_aliases::NickAliases;
+use crate::phrase_templates::PhraseTemplates;
+use crate::playback_backend::{create_backend, BackendKind};
 use crate::resource_catalogue::ResourceCatalogue;
+use crate::saved_volume::SavedVolume;
 use crate::schedule::Scheduler;
-use crate::volume_controller::VolumeController;
+use crate::stations::StationRegistry;
+use crate::stream_resolver::StreamResolver;
+use crate::tts::{Tts, TtsEngine};
+use crate::volume_backend::{create_volume_backend, MixerConfig, VolumeBackendKind};
+use crate::volume_caps::VolumeCaps;
+use crate::volume_controller::{FadePolicy, VolumeController};
+use crate::volume_history::VolumeHistory;
 use anyhow::Context;
 use clap::Parser;
+use http_request_handler::Services;
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper_util::rt::TokioIo;
 use log::*;
-use player::Player;
+use player::{InterruptionMode, Player, ReconnectPolicy, SourceGains, DEFAULT_DUCK_DB};
 use std::fmt::Debug;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
 
 #[derive(Parser, Debug)]
@@ -30,12 +66,160 @@ struct Args {
     socket_addr: String,
     #[arg(short, long, default_value = "/opt/autogrzybke_resources")]
     autogrzybke_resources_path: String,
-    #[arg(short, long, default_value = "ffplay")]
-    ffplay_path: String,
-    #[arg(long, default_value = "33")]
-    prefix_chance_percent: u64,
-    #[arg(long, default_value = "33")]
-    suffix_chance_percent: u64,
+    #[arg(short, long, value_enum, default_value_t = BackendKind::Ffplay)]
+    backend: BackendKind,
+    /// Path to the player program of the selected backend, defaults to its name
+    #[arg(short, long, alias = "ffplay-path")]
+    player_path: Option<String>,
+    /// Yaml file with the autogrzybke call-outs, see `src/autogrzybke_templates_default.yaml`
+    #[arg(
+        long,
+        default_value = "/opt/fosiaudio_chilli/autogrzybke_templates.yaml"
+    )]
+    autogrzybke_templates_path: PathBuf,
+    /// Minutes after which autogrzybke forgets past call-outs, for interludes and shoutout levels,
+    /// at most a day
+    #[arg(long, default_value = "15", value_parser = clap::value_parser!(u64).range(1..=1440))]
+    autogrzybke_window_mins: u64,
+    /// Yaml file mapping sample keys to the other spellings of the nick, like `kuba: [jakub, kuba_]`
+    #[arg(long, default_value = "/opt/fosiaudio_chilli/nick_aliases.yaml")]
+    nick_aliases_path: PathBuf,
+    /// Speaks nicks that have no samples instead of playing the "unknown" sample
+    #[arg(long, value_enum, default_value_t = TtsEngine::None)]
+    tts_engine: TtsEngine,
+    /// Path to the program of the text to speech engine, defaults to its name
+    #[arg(long)]
+    tts_program_path: Option<String>,
+    /// espeak-ng voice variant like `m3`, or the `.onnx` voice model for piper
+    #[arg(long)]
+    tts_voice: Option<String>,
+    /// Language espeak-ng speaks in
+    #[arg(long, default_value = "pl")]
+    tts_language: String,
+    /// Where synthesized nicks are kept
+    #[arg(long, default_value = "/opt/fosiaudio_chilli/tts_cache")]
+    tts_cache_dir: PathBuf,
+    /// How many synthesized nicks are kept, the oldest are removed first
+    #[arg(long, default_value = "500")]
+    tts_cache_max_files: usize,
+    #[arg(long, value_enum, default_value_t = InterruptionMode::Pause)]
+    interruption_mode: InterruptionMode,
+    /// Gain in dB of current playback while an announcement plays in duck mode
+    #[arg(long, default_value_t = DEFAULT_DUCK_DB, allow_negative_numbers = true)]
+    duck_db: f64,
+    /// How many times in a row a dropped live stream is restarted, 0 disables reconnecting
+    #[arg(long, default_value = "5")]
+    reconnect_max_attempts: u32,
+    /// Delay before the first reconnect attempt, doubled with every next one up to a minute
+    #[arg(long, default_value = "2", value_parser = clap::value_parser!(u32).range(1..=60))]
+    reconnect_base_delay_secs: u32,
+    /// Gain in dB applied to stations and jukebox content, negative is quieter
+    #[arg(long, default_value = "0", allow_negative_numbers = true)]
+    radio_gain_db: f64,
+    /// Gain in dB applied to autohypys samples
+    #[arg(long, default_value = "0", allow_negative_numbers = true)]
+    schedule_gain_db: f64,
+    /// Gain in dB applied to autogrzybke announcements
+    #[arg(long, default_value = "0", allow_negative_numbers = true)]
+    autogrzybke_gain_db: f64,
+    /// Gain in dB applied to Benny
+    #[arg(long, default_value = "0", allow_negative_numbers = true)]
+    benny_gain_db: f64,
+    /// Integrated loudness (EBU R128) autogrzybke resources are normalized to
+    #[arg(long, default_value = "-16", allow_negative_numbers = true)]
+    loudness_target_lufs: f64,
+    /// File keeping measured loudness, so only new or changed resources are analyzed
+    #[arg(long, default_value = "/opt/fosiaudio_chilli/loudness_cache.json")]
+    loudness_cache_path: PathBuf,
+    /// ffmpeg used for loudness analysis, normalization is skipped when it's missing
+    #[arg(long, default_value = "ffmpeg")]
+    ffmpeg_path: String,
+    /// Predefined hooks run around playback, extended by --pre-play-hook and --post-stop-hook
+    #[arg(long, value_enum, default_value_t = HookProfile::Raspotify)]
+    hook_profile: HookProfile,
+    /// Shell command run before playback starts, can be given multiple times
+    #[arg(long)]
+    pre_play_hook: Vec<String>,
+    /// Shell command run after playback stops, can be given multiple times
+    #[arg(long)]
+    post_stop_hook: Vec<String>,
+    /// Yaml file with the radio stations shown on the root page, created on the first change
+    #[arg(long, default_value = "/opt/fosiaudio_chilli/stations.yaml")]
+    stations_path: PathBuf,
+    /// How volume is controlled, auto tries wpctl, pactl and ALSA in this order
+    #[arg(long, value_enum, default_value_t = VolumeBackendKind::Amixer)]
+    volume_backend: VolumeBackendKind,
+    /// Sink driven by the pactl and wpctl volume backends, the default sink if not given
+    #[arg(long)]
+    volume_sink: Option<String>,
+    /// Seconds over which the volume rises when a station starts, 0 disables
+    #[arg(long, default_value = "2", value_parser = parse_secs)]
+    fade_in_secs: Duration,
+    /// Seconds over which the volume drops before playback is switched off, 0 disables
+    #[arg(long, default_value = "1", value_parser = parse_secs)]
+    fade_out_secs: Duration,
+    /// Yaml file with the quiet hours, each with `from`, `to` and `max_volume_percent`
+    #[arg(long, default_value = "/opt/fosiaudio_chilli/volume_caps.yaml")]
+    volume_caps_path: PathBuf,
+    /// File keeping the last volume people chose, restored at startup
+    #[arg(long, default_value = "/opt/fosiaudio_chilli/volume.json")]
+    volume_state_path: PathBuf,
+    /// Volume set at startup when none was saved yet
+    #[arg(long, default_value = "60", value_parser = clap::value_parser!(i32).range(0..=100))]
+    volume_default_percent: i32,
+    /// Lowest volume restored at startup
+    #[arg(long, value_parser = clap::value_parser!(i32).range(0..=100))]
+    volume_floor_percent: Option<i32>,
+    /// Highest volume restored at startup
+    #[arg(long, value_parser = clap::value_parser!(i32).range(0..=100))]
+    volume_ceiling_percent: Option<i32>,
+    /// Json lines file with the last volume changes and who made them
+    #[arg(long, default_value = "/opt/fosiaudio_chilli/volume_history.jsonl")]
+    volume_history_path: PathBuf,
+    /// How many volume changes are kept in the history
+    #[arg(long, default_value = "500")]
+    volume_history_length: usize,
+    /// Sound card of the mixer control, amixer's default card if not given
+    #[arg(long)]
+    mixer_card: Option<String>,
+    /// Simple mixer control used for volume, see `amixer scontrols`
+    #[arg(long, default_value = "SoftMaster")]
+    mixer_control: String,
+    /// Channel of the mixer control to drive, like `frontleft`, can be given multiple times.
+    /// All channels if not given
+    #[arg(long)]
+    mixer_channel: Vec<String>,
+}
+
+impl Args {
+    /// Combinations of options clap can't check on its own.
+    fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.interruption_mode == InterruptionMode::Duck && self.backend != BackendKind::Mpv {
+            anyhow::bail!(
+                "--interruption-mode duck needs --backend mpv, the others can't change the volume \
+                 while playing"
+            );
+        }
+        if let (Some(floor), Some(ceiling)) =
+            (self.volume_floor_percent, self.volume_ceiling_percent)
+        {
+            if floor > ceiling {
+                anyhow::bail!(
+                    "--volume-floor-percent {floor} is above --volume-ceiling-percent {ceiling}"
+                );
+            }
+        }
+        if self.tts_engine == TtsEngine::Piper && self.tts_voice.is_none() {
+            anyhow::bail!("--tts-engine piper needs the .onnx voice model in --tts-voice");
+        }
+        Ok(())
+    }
+}
+
+/// Fractional seconds, negative and out of range ones are refused.
+fn parse_secs(secs: &str) -> Result<Duration, String> {
+    let secs: f64 = secs.parse().map_err(|e| format!("{e}"))?;
+    Duration::try_from_secs_f64(secs).map_err(|e| e.to_string())
 }
 
 #[tokio::main]
@@ -46,6 +230,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         .init()
         .unwrap();
 
+    Args::parse().validate()?;
     let addr: SocketAddr = Args::parse().socket_addr.parse()?;
 
     let listener = TcpListener::bind(addr).await?;
@@ -53,14 +238,80 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let resources = Arc::new(
         ResourceCatalogue::try_from_dir_path(Args::parse().autogrzybke_resources_path.as_str())
             .inspect_err(|e| warn!("Failed to load resource catalogue: {e:?}. Using default."))
-            .unwrap_or(ResourceCatalogue::default()),
+            .unwrap_or_default(),
+    );
+    let player = Arc::new(
+        Player::new(
+            HookedBackend::wrap(
+                create_backend(Args::parse().backend, Args::parse().player_path.as_deref()),
+                PlaybackHooks::new(
+                    Args::parse().hook_profile,
+                    Args::parse().pre_play_hook,
+                    Args::parse().post_stop_hook,
+                ),
+            ),
+            Args::parse().interruption_mode,
+            ReconnectPolicy {
+                max_attempts: Args::parse().reconnect_max_attempts,
+                base_delay: chrono::Duration::seconds(
+                    Args::parse().reconnect_base_delay_secs.into(),
+                ),
+                stable_after: chrono::Duration::seconds(60),
+            },
+            SourceGains {
+                radio: Args::parse().radio_gain_db,
+                schedule: Args::parse().schedule_gain_db,
+                autogrzybke: Args::parse().autogrzybke_gain_db,
+                benny: Args::parse().benny_gain_db,
+            },
+            resources.clone(),
+        )
+        .with_duck_db(Args::parse().duck_db),
     );
-    let player = Arc::new(Player::new(Args::parse().ffplay_path.as_str()));
-    let volume_controller = Arc::new(VolumeController::new());
+    let mixer = MixerConfig {
+        card: Args::parse().mixer_card,
+        control: Args::parse().mixer_control,
+        channels: Args::parse().mixer_channel,
+    };
+    let volume_controller = Arc::new(VolumeController::new(
+        create_volume_backend(
+            Args::parse().volume_backend,
+            mixer,
+            Args::parse().volume_sink,
+        )?,
+        FadePolicy {
+            fade_in: Args::parse().fade_in_secs,
+            fade_out: Args::parse().fade_out_secs,
+        },
+        VolumeCaps::load(&Args::parse().volume_caps_path)?,
+        Some(SavedVolume {
+            path: Args::parse().volume_state_path,
+            default_percent: Args::parse().volume_default_percent,
+            floor_percent: Args::parse().volume_floor_percent,
+            ceiling_percent: Args::parse().volume_ceiling_percent,
+        }),
+        Some(VolumeHistory::load(
+            &Args::parse().volume_history_path,
+            Args::parse().volume_history_length,
+        )?),
+    ));
+    volume_controller
+        .restore_saved_volume()
+        .unwrap_or_else(|e| error!("Failed to restore volume: {e:#}"));
     let autogrzybke = Arc::new(Autogrzybke::new(
         resources.clone(),
-        Args::parse().prefix_chance_percent,
-        Args::parse().suffix_chance_percent,
+        NickAliases::load(&Args::parse().nick_aliases_path)?,
+        (Args::parse().tts_engine != TtsEngine::None).then(|| Tts {
+            engine: Args::parse().tts_engine,
+            program_path: Args::parse().tts_program_path,
+            voice: Args::parse().tts_voice,
+            language: Args::parse().tts_language,
+            cache_dir: Args::parse().tts_cache_dir,
+            max_cached_files: Args::parse().tts_cache_max_files,
+            ffmpeg_path: Args::parse().ffmpeg_path,
+        }),
+        PhraseTemplates::load(&Args::parse().autogrzybke_templates_path)?,
+        Duration::from_secs(60 * Args::parse().autogrzybke_window_mins),
     ));
 
     let scheduler =
@@ -68,42 +319,70 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
     let benny = Arc::new(Benny::new(player.clone(), resources.random_sample("benny")));
 
+    let resources2 = resources.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut analyzer = LoudnessAnalyzer::new(
+            &Args::parse().ffmpeg_path,
+            &Args::parse().loudness_cache_path,
+        );
+        resources2.analyze_loudness(&mut analyzer, Args::parse().loudness_target_lufs);
+    });
+
+    let stream_resolver = Arc::new(StreamResolver::new()?);
+
+    let player2 = player.clone();
+    let stream_resolver2 = stream_resolver.clone();
+    tokio::task::spawn(async move {
+        player2.run_supervisor(stream_resolver2).await;
+    });
+
+    let volume_controller2 = volume_controller.clone();
+    tokio::task::spawn(async move {
+        volume_controller2.run_caps().await;
+    });
+
     let scheduler2 = scheduler.clone();
     tokio::task::spawn(async move {
         scheduler2.clone().run_schedule().await;
     });
 
+    let icy_metadata = Arc::new(IcyMetadata::new()?);
+    let icy_metadata2 = icy_metadata.clone();
+    let player3 = player.clone();
+    tokio::task::spawn(async move {
+        icy_metadata2.run(player3).await;
+    });
+
+    let services = Arc::new(Services {
+        player,
+        volume_controller,
+        autogrzybke,
+        scheduler,
+        benny,
+        resources_catalogue: resources,
+        stream_resolver,
+        icy_metadata,
+        stations: Arc::new(StationRegistry::load(&Args::parse().stations_path)?),
+    });
+
     loop {
-        let (stream, _) = listener.accept().await?;
+        let (stream, remote_addr) = listener.accept().await?;
 
         // Use an adapter to access something implementing `tokio::io` traits as if they implement
         // `hyper::rt` IO traits.
         let io = TokioIo::new(stream);
 
-        let player = player.clone();
-        let volume_controller = volume_controller.clone();
-        let autogrzybke = autogrzybke.clone();
-        let scheduler = scheduler.clone();
-        let benny = benny.clone();
-        let resources = resources.clone();
+        let services = services.clone();
 
         // Spawn a tokio task to serve multiple connections concurrently
-        tokio::task::spawn(async {
+        tokio::task::spawn(async move {
             // Finally, we bind the incoming connection to our `hello` service
             if let Err(err) = http1::Builder::new()
                 // `service_fn` converts our function in a `Service`
                 .serve_connection(
                     io,
                     service_fn(move |request| {
-                        http_request_handler::handle_request(
-                            request,
-                            player.clone(),
-                            volume_controller.clone(),
-                            autogrzybke.clone(),
-                            scheduler.clone(),
-                            benny.clone(),
-                            resources.clone(),
-                        )
+                        http_request_handler::handle_request(request, services.clone(), remote_addr)
                     }),
                 )
                 .await
@@ -113,3 +392,40 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         });
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn validate(args: &[&str]) -> Result<(), anyhow::Error> {
+        Args::try_parse_from([&["fosiaudio_chilli"], args].concat())
+            .unwrap()
+            .validate()
+    }
+
+    #[test]
+    fn validate_test() {
+        assert!(validate(&[]).is_ok());
+        assert!(validate(&[
+            "--volume-floor-percent",
+            "40",
+            "--volume-ceiling-percent",
+            "40"
+        ])
+        .is_ok());
+        assert!(validate(&[
+            "--volume-floor-percent",
+            "50",
+            "--volume-ceiling-percent",
+            "40"
+        ])
+        .is_err());
+        assert!(
+            Args::try_parse_from(["fosiaudio_chilli", "--volume-ceiling-percent", "101"]).is_err()
+        );
+        assert!(
+            Args::try_parse_from(["fosiaudio_chilli", "--autogrzybke-window-mins", "1441"])
+                .is_err()
+        );
+    }
+}
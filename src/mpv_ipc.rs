@@ -0,0 +1,113 @@
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const IPC_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Client for mpv's JSON IPC protocol, see https://mpv.io/manual/stable/#json-ipc .
+/// Connects on every command, so it doesn't care whether mpv has already created the socket
+/// when the client is made.
+pub struct MpvIpc {
+    socket_path: PathBuf,
+    next_request_id: u64,
+    timeout: Duration,
+}
+
+impl MpvIpc {
+    pub fn new(socket_path: &Path) -> Self {
+        MpvIpc {
+            socket_path: socket_path.to_path_buf(),
+            next_request_id: 1,
+            timeout: IPC_TIMEOUT,
+        }
+    }
+
+    /// How long to wait for mpv to take and answer each command.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sends a command and returns the `data` of mpv's reply.
+    pub fn command(&mut self, command: Value) -> Result<Value, Error> {
+        let request_id = self.next_request_id;
+        self.next_request_id += 1;
+
+        let mut stream = UnixStream::connect(&self.socket_path)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        let request = json!({"command": command, "request_id": request_id});
+        writeln!(stream, "{request}")?;
+
+        // mpv interleaves asynchronous events with replies, skip everything that isn't ours.
+        for line in BufReader::new(stream).lines() {
+            let reply: Value = serde_json::from_str(&line?)?;
+            if reply["request_id"] != request_id {
+                continue;
+            }
+            return match reply["error"].as_str() {
+                Some("success") => Ok(reply["data"].clone()),
+                Some(error) => Err(Error::other(format!(
+                    "mpv command {command} failed: {error}"
+                ))),
+                None => Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Malformed mpv reply: {reply}"),
+                )),
+            };
+        }
+        Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            format!("mpv closed IPC connection before replying to {command}"),
+        ))
+    }
+
+    pub fn get_property(&mut self, name: &str) -> Result<Value, Error> {
+        self.command(json!(["get_property", name]))
+    }
+
+    pub fn set_property(&mut self, name: &str, value: Value) -> Result<(), Error> {
+        self.command(json!(["set_property", name, value]))
+            .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn command_skips_events_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("mpv.sock");
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = String::new();
+            BufReader::new(stream.try_clone().unwrap())
+                .read_line(&mut request)
+                .unwrap();
+            writeln!(stream, r#"{{"event":"playback-restart"}}"#).unwrap();
+            writeln!(
+                stream,
+                r#"{{"data":12.5,"request_id":1,"error":"success"}}"#
+            )
+            .unwrap();
+            let mut rest = String::new();
+            let _ = stream.read_to_string(&mut rest);
+            request
+        });
+
+        let mut ipc = MpvIpc::new(&socket_path);
+        assert_eq!(ipc.get_property("time-pos").unwrap(), json!(12.5));
+        let request: Value = serde_json::from_str(&server.join().unwrap()).unwrap();
+        assert_eq!(
+            request,
+            json!({"command": ["get_property", "time-pos"], "request_id": 1})
+        );
+    }
+}
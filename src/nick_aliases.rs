@@ -0,0 +1,76 @@
+use anyhow::{anyhow, bail, Context};
+use log::*;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Maps the many spellings of a nick to the one sample key it's announced with. The yaml file
+/// lists the spellings under each key:
+/// ```yaml
+/// kuba: [kuba_, jakub, kubus]
+/// ```
+/// Case doesn't matter, nicks without an alias are looked up as typed.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct NickAliases(HashMap<String, String>);
+
+fn normalize(nick: &str) -> String {
+    nick.trim().to_lowercase()
+}
+
+impl NickAliases {
+    pub fn parse(text: &str) -> Result<Self, anyhow::Error> {
+        let keys: HashMap<String, Vec<String>> =
+            serde_yaml::from_str(text).context("Parse nick aliases")?;
+        let mut aliases = HashMap::new();
+        for (key, spellings) in keys {
+            let key = normalize(&key);
+            for spelling in spellings {
+                let spelling = normalize(&spelling);
+                if let Some(other) = aliases.insert(spelling.clone(), key.clone()) {
+                    if other != key {
+                        bail!("Nick {spelling} is an alias of both {other} and {key}");
+                    }
+                }
+            }
+        }
+        Ok(NickAliases(aliases))
+    }
+
+    /// No aliases if the file doesn't exist.
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => {
+                let aliases =
+                    NickAliases::parse(&text).context(format!("Load {}", path.display()))?;
+                info!("{} nick aliases", aliases.0.len());
+                Ok(aliases)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!("{} not found, no nick aliases", path.display());
+                Ok(NickAliases::default())
+            }
+            Err(e) => Err(anyhow!(e).context(format!("Read {}", path.display()))),
+        }
+    }
+
+    /// The sample key the nick is announced with.
+    pub fn resolve(&self, nick: &str) -> String {
+        let nick = normalize(nick);
+        self.0.get(&nick).cloned().unwrap_or(nick)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn resolve_test() {
+        let aliases = NickAliases::parse("Kuba: [kuba_, JAKUB]\nhypys: [hypcio]").unwrap();
+        assert_eq!(aliases.resolve("Kuba"), "kuba");
+        assert_eq!(aliases.resolve("kuba_"), "kuba");
+        assert_eq!(aliases.resolve("Jakub"), "kuba");
+        assert_eq!(aliases.resolve("hypcio"), "hypys");
+        assert_eq!(aliases.resolve("Alpinus"), "alpinus");
+        assert!(NickAliases::parse("a: [x]\nb: [X]").is_err());
+    }
+}
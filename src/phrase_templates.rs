@@ -0,0 +1,405 @@
+use anyhow::{anyhow, bail, Context};
+use log::*;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::autogrzybke::AutogrzybkeRequest;
+
+pub const PHRASE_TEMPLATES_DEFAULT: &str = include_str!("autogrzybke_templates_default.yaml");
+
+/// Stands for the sample of the nick in shoutouts.
+const NICK_PLACEHOLDER: &str = "$nick";
+
+/// Request flags that leave out optional parts.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipFlag {
+    Lobby,
+    Prefix,
+    Suffix,
+    Interlude,
+}
+
+impl SkipFlag {
+    fn is_set(self, req: &AutogrzybkeRequest) -> bool {
+        match self {
+            SkipFlag::Lobby => req.skip_lobby,
+            SkipFlag::Prefix => req.skip_prefix,
+            SkipFlag::Suffix => req.skip_suffix,
+            SkipFlag::Interlude => req.skip_interlude,
+        }
+    }
+}
+
+fn default_chance_percent() -> u64 {
+    100
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct OptionalPart {
+    pub optional: Vec<Part>,
+    #[serde(default = "default_chance_percent")]
+    pub chance_percent: u64,
+    #[serde(default)]
+    pub skip: Option<SkipFlag>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Part {
+    Sample(String),
+    Optional(OptionalPart),
+}
+
+/// Shoutout for nicks missing `from_streak` or more calls in a row.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ShoutoutLevel {
+    pub name: String,
+    pub from_streak: u32,
+    /// The shoutout's pad if not given.
+    #[serde(default)]
+    pub pad: Option<String>,
+    pub parts: Vec<Part>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Shoutout {
+    #[serde(default)]
+    pub pad: Option<String>,
+    pub parts: Vec<Part>,
+    /// Ascending by `from_streak`, the shoutout itself is used below the first one.
+    #[serde(default)]
+    pub levels: Vec<ShoutoutLevel>,
+}
+
+impl Shoutout {
+    fn level(&self, streak: u32) -> Option<&ShoutoutLevel> {
+        self.levels
+            .iter()
+            .rev()
+            .find(|level| streak >= level.from_streak)
+    }
+
+    fn pad_and_parts(&self, streak: u32) -> (Option<&String>, &[Part]) {
+        match self.level(streak) {
+            Some(level) => (level.pad.as_ref().or(self.pad.as_ref()), &level.parts),
+            None => (self.pad.as_ref(), &self.parts),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Escalation {
+    pub parts: Vec<Part>,
+    pub first_at_use: i64,
+    pub every_uses: i64,
+    #[serde(default)]
+    pub skip: Option<SkipFlag>,
+}
+
+impl Escalation {
+    /// How many interludes the `usage_count`th call-out within the window gets.
+    fn count(&self, usage_count: i64) -> usize {
+        match usage_count >= self.first_at_use {
+            true => ((usage_count - self.first_at_use) / self.every_uses + 1) as usize,
+            false => 0,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct WaitingTemplate {
+    #[serde(default)]
+    pub intro: Vec<Part>,
+    pub shoutout: Shoutout,
+    #[serde(default)]
+    pub escalation: Option<Escalation>,
+    #[serde(default)]
+    pub outro: Vec<Part>,
+}
+
+/// Word sequences autogrzybke plays, loaded from yaml so new call-outs need no recompiling.
+/// See `autogrzybke_templates_default.yaml` for the format.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PhraseTemplates {
+    pub ready: Vec<Part>,
+    pub waiting: WaitingTemplate,
+}
+
+impl PhraseTemplates {
+    pub fn parse(text: &str) -> Result<Self, anyhow::Error> {
+        let templates: PhraseTemplates =
+            serde_yaml::from_str(text).context("Parse phrase templates")?;
+        templates.validate()?;
+        Ok(templates)
+    }
+
+    /// The built-in templates if the file doesn't exist.
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => PhraseTemplates::parse(&text).context(format!("Load {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!(
+                    "{} not found, using default phrase templates",
+                    path.display()
+                );
+                PhraseTemplates::parse(PHRASE_TEMPLATES_DEFAULT)
+            }
+            Err(e) => Err(anyhow!(e).context(format!("Read {}", path.display()))),
+        }
+    }
+
+    fn validate(&self) -> Result<(), anyhow::Error> {
+        fn check(parts: &[Part], nick_allowed: bool) -> Result<(), anyhow::Error> {
+            for part in parts {
+                match part {
+                    Part::Sample(sample) if sample == NICK_PLACEHOLDER && !nick_allowed => {
+                        bail!("{NICK_PLACEHOLDER} is only allowed in the shoutout")
+                    }
+                    Part::Sample(_) => (),
+                    Part::Optional(part) => {
+                        if part.chance_percent > 100 {
+                            bail!("Chance out of 0..=100: {}", part.chance_percent);
+                        }
+                        check(&part.optional, nick_allowed)?;
+                    }
+                }
+            }
+            Ok(())
+        }
+        check(&self.ready, false)?;
+        check(&self.waiting.intro, false)?;
+        check(&self.waiting.shoutout.parts, true)?;
+        let mut previous_streak = 0;
+        for level in &self.waiting.shoutout.levels {
+            check(&level.parts, true)?;
+            if level.from_streak <= previous_streak {
+                bail!(
+                    "Shoutout levels need ascending positive from_streak: {}",
+                    level.name
+                );
+            }
+            previous_streak = level.from_streak;
+        }
+        check(&self.waiting.outro, false)?;
+        if let Some(escalation) = &self.waiting.escalation {
+            check(&escalation.parts, false)?;
+            if escalation.first_at_use < 1 || escalation.every_uses < 1 {
+                bail!("Escalation needs positive first_at_use and every_uses: {escalation:?}");
+            }
+        }
+        Ok(())
+    }
+
+    pub fn ready_sequence(&self, req: &AutogrzybkeRequest) -> Vec<String> {
+        let mut words = Vec::new();
+        expand(&self.ready, None, req, &mut rand::rng(), &mut words);
+        words
+    }
+
+    /// Name of the shoutout level for a nick missing `streak` calls in a row, if any.
+    pub fn level_name(&self, streak: u32) -> Option<&str> {
+        self.waiting
+            .shoutout
+            .level(streak)
+            .map(|level| level.name.as_str())
+    }
+
+    /// Shoutouts of the nicks' samples, at the level of their missing streak, mixed with
+    /// escalation interludes, in random order, between the intro and the outro.
+    pub fn waiting_sequence(
+        &self,
+        nicks: &[(String, u32)],
+        usage_count: i64,
+        req: &AutogrzybkeRequest,
+    ) -> Vec<String> {
+        let template = &self.waiting;
+        let mut rng = rand::rng();
+        let mut chunks: Vec<Vec<String>> = nicks
+            .iter()
+            .map(|(sample_key, streak)| {
+                let (pad, parts) = template.shoutout.pad_and_parts(*streak);
+                let mut shoutout = Vec::new();
+                let chosen = expand(parts, Some(sample_key), req, &mut rng, &mut shoutout);
+                match pad {
+                    Some(pad) if chosen => {
+                        [vec![pad.clone()], shoutout, vec![pad.clone()]].concat()
+                    }
+                    _ => shoutout,
+                }
+            })
+            .collect();
+        if let Some(escalation) = &template.escalation {
+            if !escalation.skip.is_some_and(|flag| flag.is_set(req)) {
+                for _ in 0..escalation.count(usage_count) {
+                    let mut interlude = Vec::new();
+                    expand(&escalation.parts, None, req, &mut rng, &mut interlude);
+                    chunks.push(interlude);
+                }
+            }
+        }
+        chunks.shuffle(&mut rng);
+        let mut words = Vec::new();
+        expand(&template.intro, None, req, &mut rng, &mut words);
+        words.extend(chunks.into_iter().flatten());
+        expand(&template.outro, None, req, &mut rng, &mut words);
+        words
+    }
+}
+
+/// Appends the samples of the parts. Returns whether any optional part was chosen.
+fn expand(
+    parts: &[Part],
+    nick: Option<&str>,
+    req: &AutogrzybkeRequest,
+    rng: &mut impl Rng,
+    words: &mut Vec<String>,
+) -> bool {
+    let mut chosen = false;
+    for part in parts {
+        match part {
+            Part::Sample(sample) if sample == NICK_PLACEHOLDER => {
+                words.extend(nick.map(String::from));
+            }
+            Part::Sample(sample) => words.push(sample.clone()),
+            Part::Optional(part) => {
+                if rng.random_range(0..100) < part.chance_percent
+                    && !part.skip.is_some_and(|flag| flag.is_set(req))
+                {
+                    chosen = true;
+                    expand(&part.optional, nick, req, rng, words);
+                }
+            }
+        }
+    }
+    chosen
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(skip_lobby: bool, skip_interlude: bool) -> AutogrzybkeRequest {
+        AutogrzybkeRequest {
+            missing: Vec::new(),
+            skip_lobby,
+            skip_prefix: false,
+            skip_suffix: false,
+            skip_interlude,
+        }
+    }
+
+    #[test]
+    fn default_templates_test() {
+        let templates = PhraseTemplates::parse(PHRASE_TEMPLATES_DEFAULT).unwrap();
+        assert_eq!(
+            templates.ready_sequence(&request(false, false)),
+            ["noise", "everyone", "ready"]
+        );
+        let escalation = templates.waiting.escalation.as_ref().unwrap();
+        // Same as the formerly hardcoded `(usage_count - 1) / 2 - 1`.
+        for usage_count in 1..20 {
+            assert_eq!(
+                escalation.count(usage_count),
+                0.max((usage_count - 1) / 2 - 1) as usize
+            );
+        }
+    }
+
+    #[test]
+    fn waiting_sequence_test() {
+        let templates = PhraseTemplates::parse(
+            r#"
+ready: [ready]
+waiting:
+  intro: [noise]
+  shoutout:
+    pad: silence
+    parts:
+      - optional: [prefix]
+        skip: prefix
+      - $nick
+  escalation: {parts: [kurwa], first_at_use: 2, every_uses: 1, skip: interlude}
+  outro:
+    - optional: [lobby]
+      skip: lobby
+"#,
+        )
+        .unwrap();
+        let words =
+            templates.waiting_sequence(&[("kuba".to_string(), 1)], 3, &request(true, false));
+        assert_eq!(words[0], "noise");
+        let mut middle = words[1..].to_vec();
+        middle.sort();
+        assert_eq!(
+            middle,
+            ["kuba", "kurwa", "kurwa", "prefix", "silence", "silence"]
+        );
+        let words =
+            templates.waiting_sequence(&[("kuba".to_string(), 1)], 3, &request(false, true));
+        assert_eq!(
+            words,
+            ["noise", "silence", "prefix", "kuba", "silence", "lobby"]
+        );
+
+        let templates = PhraseTemplates::parse(
+            r#"
+ready: [ready]
+waiting:
+  shoutout:
+    pad: silence
+    parts: [$nick]
+    levels:
+      - {name: annoyed, from_streak: 2, parts: [$nick, annoyed]}
+      - {name: furious, from_streak: 4, pad: noise, parts: [furious, $nick]}
+"#,
+        )
+        .unwrap();
+        let nicks = |streak| [("kuba".to_string(), streak)];
+        let req = request(false, false);
+        assert_eq!(templates.waiting_sequence(&nicks(1), 1, &req), ["kuba"]);
+        assert_eq!(templates.level_name(1), None);
+        assert_eq!(
+            templates.waiting_sequence(&nicks(3), 1, &req),
+            ["kuba", "annoyed"]
+        );
+        assert_eq!(templates.level_name(3), Some("annoyed"));
+        assert_eq!(
+            templates.waiting_sequence(&nicks(9), 1, &req),
+            ["furious", "kuba"]
+        );
+        assert_eq!(templates.level_name(9), Some("furious"));
+        assert!(PhraseTemplates::parse(
+            r#"
+ready: []
+waiting:
+  shoutout:
+    parts: [$nick]
+    levels:
+      - {name: furious, from_streak: 4, parts: [$nick]}
+      - {name: annoyed, from_streak: 2, parts: [$nick]}
+"#
+        )
+        .is_err());
+
+        assert!(
+            PhraseTemplates::parse("ready: [$nick]\nwaiting: {shoutout: {parts: []}}").is_err()
+        );
+        assert!(PhraseTemplates::parse(
+            "ready: []\nwaiting: {shoutout: {parts: [{optional: [a], chance_percent: 101}]}}"
+        )
+        .is_err());
+        assert!(PhraseTemplates::parse(
+            "ready: []\nwaiting: {shoutout: {parts: [{optional: [a], chance: 50}]}}"
+        )
+        .is_err());
+    }
+}
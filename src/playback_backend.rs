@@ -0,0 +1,615 @@
+use crate::mpv_ipc::MpvIpc;
+use log::*;
+use serde::Serialize;
+use serde_json::json;
+use std::io::{ErrorKind, Write};
+use std::process::{Child, Command, ExitStatus};
+use std::str::FromStr;
+use std::time::Duration;
+use tempfile::{NamedTempFile, TempDir};
+
+/// What a backend is asked to play.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PlaybackSource {
+    Url {
+        url: String,
+        seek_pos: chrono::Duration,
+    },
+    Files(Vec<PlaylistFile>),
+}
+
+/// Local file with its own gain on top of the playback gain, used for loudness normalization.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlaylistFile {
+    pub path: String,
+    pub gain_db: f64,
+}
+
+impl From<&str> for PlaylistFile {
+    fn from(path: &str) -> Self {
+        PlaylistFile {
+            path: path.to_string(),
+            gain_db: 0.0,
+        }
+    }
+}
+
+fn has_file_gains(playlist: &[PlaylistFile]) -> bool {
+    playlist.iter().any(|file| file.gain_db != 0.0)
+}
+
+impl PlaybackSource {
+    /// Two sources play the same content if they point at the same url, regardless of seek.
+    /// Playlists are never considered the same, starting one again restarts it.
+    pub fn is_same_content(&self, other: &PlaybackSource) -> bool {
+        match (self, other) {
+            (PlaybackSource::Url { url, .. }, PlaybackSource::Url { url: other, .. }) => {
+                url == other
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Starts playback processes for a particular player program.
+pub trait PlaybackBackend: Send + Sync {
+    fn name(&self) -> &'static str;
+    /// `gain_db` is applied by the player program on top of the mixer volume.
+    fn start(
+        &self,
+        source: &PlaybackSource,
+        gain_db: f64,
+    ) -> Result<Box<dyn PlaybackProcess>, std::io::Error>;
+}
+
+/// Asks a running playback where it is. It doesn't borrow the process, so it can run without
+/// holding up the player while the program takes its time to answer.
+pub type PositionQuery =
+    Box<dyn FnOnce() -> Result<Option<PlaybackPosition>, std::io::Error> + Send>;
+
+/// Handle to a running playback, owned by `Player`.
+/// Only `id`, `try_wait` and `kill` are mandatory, the rest is reported as unsupported unless a
+/// backend can control its process while it's running.
+pub trait PlaybackProcess: Send {
+    fn id(&self) -> u32;
+    fn try_wait(&mut self) -> Result<Option<ExitStatus>, std::io::Error>;
+    /// Stops playback and waits for it to go away.
+    fn kill(&mut self) -> Result<(), std::io::Error>;
+
+    fn set_paused(&mut self, _paused: bool) -> Result<(), std::io::Error> {
+        Err(unsupported("pause"))
+    }
+
+    fn seek(&mut self, _target: SeekTarget) -> Result<(), std::io::Error> {
+        Err(unsupported("seek"))
+    }
+
+    fn playlist_next(&mut self) -> Result<(), std::io::Error> {
+        Err(unsupported("playlist-next"))
+    }
+
+    fn playlist_prev(&mut self) -> Result<(), std::io::Error> {
+        Err(unsupported("playlist-prev"))
+    }
+
+    /// Volume in dB on top of the gain playback was started with, 0 puts it back.
+    fn set_volume_db(&mut self, _volume_db: f64) -> Result<(), std::io::Error> {
+        Err(unsupported("volume"))
+    }
+
+    /// None if the backend can't tell.
+    fn position_query(&self) -> Option<PositionQuery> {
+        None
+    }
+}
+
+fn unsupported(operation: &str) -> std::io::Error {
+    std::io::Error::new(
+        ErrorKind::Unsupported,
+        format!("{operation} is not supported by this backend"),
+    )
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SeekTarget {
+    Absolute(f64),
+    Relative(f64),
+}
+
+impl FromStr for SeekTarget {
+    type Err = std::num::ParseFloatError;
+
+    /// "90" seeks to 1:30, "+10" and "-10" skip forward and back by 10 seconds.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let seconds: f64 = s.parse()?;
+        if s.starts_with('+') || s.starts_with('-') {
+            Ok(SeekTarget::Relative(seconds))
+        } else {
+            Ok(SeekTarget::Absolute(seconds))
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct PlaybackPosition {
+    pub position_secs: f64,
+    pub duration_secs: Option<f64>,
+    pub playlist_pos: Option<u64>,
+    pub playlist_count: Option<u64>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum BackendKind {
+    Ffplay,
+    Mpv,
+    Cvlc,
+}
+
+pub fn create_backend(kind: BackendKind, program_path: Option<&str>) -> Box<dyn PlaybackBackend> {
+    match kind {
+        BackendKind::Ffplay => Box::new(FfplayBackend::new(program_path.unwrap_or("ffplay"))),
+        BackendKind::Mpv => Box::new(MpvBackend::new(program_path.unwrap_or("mpv"))),
+        BackendKind::Cvlc => Box::new(CvlcBackend::new(program_path.unwrap_or("cvlc"))),
+    }
+}
+
+/// A spawned player program together with whatever files it needs to stay around.
+struct ChildProcess {
+    child: Child,
+    #[allow(dead_code)]
+    playlist_handle: Option<NamedTempFile>,
+}
+
+impl PlaybackProcess for ChildProcess {
+    fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    fn try_wait(&mut self) -> Result<Option<ExitStatus>, std::io::Error> {
+        self.child.try_wait()
+    }
+
+    fn kill(&mut self) -> Result<(), std::io::Error> {
+        self.child.kill()?;
+        let _ = self.child.wait();
+        Ok(())
+    }
+}
+
+fn format_seek_pos(seek_pos: chrono::Duration) -> String {
+    format!("{:.3}", seek_pos.num_milliseconds().max(0) as f64 / 1000.0)
+}
+
+/// Escapes a filter option value, then the result once more for the filtergraph around it.
+fn escape_filter_arg(value: &str) -> String {
+    let escape = |value: &str, special: &[char]| {
+        value.chars().fold(String::new(), |mut acc, c| {
+            if special.contains(&c) {
+                acc.push('\\');
+            }
+            acc.push(c);
+            acc
+        })
+    };
+    escape(
+        &escape(value, &['\\', '\'', ':']),
+        &['\\', '\'', '[', ']', ',', ';'],
+    )
+}
+
+/// Filtergraph reading the files one after another, each through its own volume filter.
+/// Files are resampled to a common format, concat refuses to join differing ones.
+fn concat_filtergraph(playlist: &[PlaylistFile]) -> String {
+    let inputs: String = playlist
+        .iter()
+        .enumerate()
+        .map(|(i, file)| {
+            format!(
+                "amovie={},volume={}dB,aresample=48000,aformat=channel_layouts=stereo[a{i}];",
+                escape_filter_arg(&file.path),
+                file.gain_db
+            )
+        })
+        .collect();
+    let labels: String = (0..playlist.len()).map(|i| format!("[a{i}]")).collect();
+    format!("{inputs}{labels}concat=n={}:v=0:a=1[out0]", playlist.len())
+}
+
+pub struct FfplayBackend {
+    ffplay_path: String,
+}
+
+impl FfplayBackend {
+    pub fn new(ffplay_path: &str) -> Self {
+        FfplayBackend {
+            ffplay_path: ffplay_path.to_string(),
+        }
+    }
+
+    fn command(
+        &self,
+        source: &PlaybackSource,
+        gain_db: f64,
+    ) -> Result<(Command, Option<NamedTempFile>), std::io::Error> {
+        let mut command = Command::new(&self.ffplay_path);
+        command.arg("-autoexit").arg("-nodisp");
+        if gain_db != 0.0 {
+            command.arg("-af").arg(format!("volume={gain_db}dB"));
+        }
+        match source {
+            PlaybackSource::Url { url, seek_pos } => {
+                let start_time = chrono::NaiveTime::from_hms_opt(0, 0, 0).unwrap() + *seek_pos;
+                info!("start_time: {}", start_time);
+                command
+                    .arg("-fflags")
+                    .arg("nobuffer")
+                    .arg("-ss")
+                    .arg(format!("{}", start_time))
+                    .arg(url);
+                Ok((command, None))
+            }
+            PlaybackSource::Files(playlist) if has_file_gains(playlist) => {
+                // The concat demuxer can't filter single files.
+                command
+                    .arg("-f")
+                    .arg("lavfi")
+                    .arg("-i")
+                    .arg(concat_filtergraph(playlist));
+                Ok((command, None))
+            }
+            PlaybackSource::Files(playlist) => {
+                let mut playlist_file = tempfile::NamedTempFile::new()?;
+                for file in playlist {
+                    writeln!(playlist_file, "file {}", file.path)?;
+                }
+                playlist_file.flush()?;
+                command
+                    .arg("-f")
+                    .arg("concat")
+                    .arg("-safe")
+                    .arg("0")
+                    .arg("-i")
+                    .arg(playlist_file.path());
+                Ok((command, Some(playlist_file)))
+            }
+        }
+    }
+}
+
+impl PlaybackBackend for FfplayBackend {
+    fn name(&self) -> &'static str {
+        "ffplay"
+    }
+
+    fn start(
+        &self,
+        source: &PlaybackSource,
+        gain_db: f64,
+    ) -> Result<Box<dyn PlaybackProcess>, std::io::Error> {
+        let (mut command, playlist_handle) = self.command(source, gain_db)?;
+        Ok(Box::new(ChildProcess {
+            child: command.spawn()?,
+            playlist_handle,
+        }))
+    }
+}
+
+pub struct MpvBackend {
+    mpv_path: String,
+}
+
+/// mpv process listening for JSON IPC commands on a socket inside its own temporary directory.
+struct MpvProcess {
+    child: Child,
+    ipc: MpvIpc,
+    socket_dir: TempDir,
+}
+
+const MPV_SOCKET_NAME: &str = "mpv.sock";
+
+/// Position queries are made every supervisor tick, a stuck mpv mustn't delay them for long.
+const MPV_POSITION_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// mpv's volume property is cubic, 100 leaves the sound unchanged.
+fn mpv_volume(volume_db: f64) -> f64 {
+    100.0 * 10f64.powf(volume_db / 60.0)
+}
+
+fn query_mpv_position(ipc: &mut MpvIpc) -> Result<Option<PlaybackPosition>, std::io::Error> {
+    let Some(position_secs) = ipc.get_property("time-pos")?.as_f64() else {
+        return Ok(None);
+    };
+    // Live streams have no duration, mpv reports that as an error.
+    let duration_secs = ipc.get_property("duration").ok();
+    Ok(Some(PlaybackPosition {
+        position_secs,
+        duration_secs: duration_secs.and_then(|d| d.as_f64()),
+        playlist_pos: ipc.get_property("playlist-pos")?.as_u64(),
+        playlist_count: ipc.get_property("playlist-count")?.as_u64(),
+    }))
+}
+
+impl MpvBackend {
+    pub fn new(mpv_path: &str) -> Self {
+        MpvBackend {
+            mpv_path: mpv_path.to_string(),
+        }
+    }
+
+    fn command(
+        &self,
+        source: &PlaybackSource,
+        gain_db: f64,
+        socket_path: &std::path::Path,
+    ) -> Command {
+        let mut command = Command::new(&self.mpv_path);
+        command.arg("--no-video").arg("--no-terminal").arg(format!(
+            "--input-ipc-server={}",
+            socket_path.to_string_lossy()
+        ));
+        if gain_db != 0.0 {
+            command.arg(format!("--af=lavfi=[volume={gain_db}dB]"));
+        }
+        match source {
+            PlaybackSource::Url { url, seek_pos } => {
+                command
+                    .arg(format!("--start=+{}", format_seek_pos(*seek_pos)))
+                    .arg("--")
+                    .arg(url);
+            }
+            PlaybackSource::Files(playlist) if has_file_gains(playlist) => {
+                // Per-file options replace the global filter, so they carry both gains.
+                for file in playlist {
+                    command
+                        .arg("--{")
+                        .arg(format!("--af=lavfi=[volume={}dB]", gain_db + file.gain_db))
+                        .arg(&file.path)
+                        .arg("--}");
+                }
+            }
+            PlaybackSource::Files(playlist) => {
+                command
+                    .arg("--")
+                    .args(playlist.iter().map(|file| &file.path));
+            }
+        }
+        command
+    }
+}
+
+impl PlaybackBackend for MpvBackend {
+    fn name(&self) -> &'static str {
+        "mpv"
+    }
+
+    fn start(
+        &self,
+        source: &PlaybackSource,
+        gain_db: f64,
+    ) -> Result<Box<dyn PlaybackProcess>, std::io::Error> {
+        let socket_dir = tempfile::tempdir()?;
+        let socket_path = socket_dir.path().join(MPV_SOCKET_NAME);
+        let child = self.command(source, gain_db, &socket_path).spawn()?;
+        Ok(Box::new(MpvProcess {
+            child,
+            ipc: MpvIpc::new(&socket_path),
+            socket_dir,
+        }))
+    }
+}
+
+impl PlaybackProcess for MpvProcess {
+    fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    fn try_wait(&mut self) -> Result<Option<ExitStatus>, std::io::Error> {
+        self.child.try_wait()
+    }
+
+    fn kill(&mut self) -> Result<(), std::io::Error> {
+        self.child.kill()?;
+        let _ = self.child.wait();
+        Ok(())
+    }
+
+    fn set_paused(&mut self, paused: bool) -> Result<(), std::io::Error> {
+        self.ipc.set_property("pause", json!(paused))
+    }
+
+    fn seek(&mut self, target: SeekTarget) -> Result<(), std::io::Error> {
+        let command = match target {
+            SeekTarget::Absolute(seconds) => json!(["seek", seconds, "absolute"]),
+            SeekTarget::Relative(seconds) => json!(["seek", seconds, "relative"]),
+        };
+        self.ipc.command(command).map(|_| ())
+    }
+
+    fn playlist_next(&mut self) -> Result<(), std::io::Error> {
+        self.ipc.command(json!(["playlist-next"])).map(|_| ())
+    }
+
+    fn playlist_prev(&mut self) -> Result<(), std::io::Error> {
+        self.ipc.command(json!(["playlist-prev"])).map(|_| ())
+    }
+
+    fn set_volume_db(&mut self, volume_db: f64) -> Result<(), std::io::Error> {
+        self.ipc
+            .set_property("volume", json!(mpv_volume(volume_db)))
+    }
+
+    fn position_query(&self) -> Option<PositionQuery> {
+        let mut ipc = MpvIpc::new(&self.socket_dir.path().join(MPV_SOCKET_NAME))
+            .with_timeout(MPV_POSITION_TIMEOUT);
+        Some(Box::new(move || query_mpv_position(&mut ipc)))
+    }
+}
+
+pub struct CvlcBackend {
+    cvlc_path: String,
+}
+
+impl CvlcBackend {
+    pub fn new(cvlc_path: &str) -> Self {
+        CvlcBackend {
+            cvlc_path: cvlc_path.to_string(),
+        }
+    }
+
+    fn command(&self, source: &PlaybackSource, gain_db: f64) -> Command {
+        let mut command = Command::new(&self.cvlc_path);
+        command.arg("--play-and-exit").arg("--no-video");
+        if gain_db != 0.0 {
+            // vlc takes a linear factor.
+            command.arg(format!("--gain={:.3}", 10f64.powf(gain_db / 20.0)));
+        }
+        match source {
+            PlaybackSource::Url { url, seek_pos } => {
+                command
+                    .arg(format!("--start-time={}", format_seek_pos(*seek_pos)))
+                    .arg(url);
+            }
+            PlaybackSource::Files(playlist) => {
+                // No per-file filters in vlc, loudness normalization is skipped.
+                command.args(playlist.iter().map(|file| &file.path));
+            }
+        }
+        command
+    }
+}
+
+impl PlaybackBackend for CvlcBackend {
+    fn name(&self) -> &'static str {
+        "cvlc"
+    }
+
+    fn start(
+        &self,
+        source: &PlaybackSource,
+        gain_db: f64,
+    ) -> Result<Box<dyn PlaybackProcess>, std::io::Error> {
+        Ok(Box::new(ChildProcess {
+            child: self.command(source, gain_db).spawn()?,
+            playlist_handle: None,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(command: &Command) -> Vec<String> {
+        command
+            .get_args()
+            .map(|a| a.to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn command_line_test() {
+        let url = PlaybackSource::Url {
+            url: "http://radio".to_string(),
+            seek_pos: chrono::Duration::milliseconds(1500),
+        };
+        let (command, playlist_handle) = FfplayBackend::new("ffplay").command(&url, 0.0).unwrap();
+        assert!(playlist_handle.is_none());
+        assert_eq!(
+            args(&command),
+            [
+                "-autoexit",
+                "-nodisp",
+                "-fflags",
+                "nobuffer",
+                "-ss",
+                "00:00:01.500",
+                "http://radio"
+            ]
+        );
+        assert_eq!(
+            args(&CvlcBackend::new("cvlc").command(&url, -6.0)),
+            [
+                "--play-and-exit",
+                "--no-video",
+                "--gain=0.501",
+                "--start-time=1.500",
+                "http://radio"
+            ]
+        );
+        let (command, _) = FfplayBackend::new("ffplay").command(&url, -3.0).unwrap();
+        assert_eq!(args(&command)[2..4], ["-af", "volume=-3dB"]);
+        let files = PlaybackSource::Files(vec!["/a.mp3".into(), "/b.mp3".into()]);
+        assert_eq!(
+            args(&MpvBackend::new("mpv").command(
+                &files,
+                3.5,
+                std::path::Path::new("/tmp/mpv.sock")
+            )),
+            [
+                "--no-video",
+                "--no-terminal",
+                "--input-ipc-server=/tmp/mpv.sock",
+                "--af=lavfi=[volume=3.5dB]",
+                "--",
+                "/a.mp3",
+                "/b.mp3"
+            ]
+        );
+    }
+
+    #[test]
+    fn file_gains_command_line_test() {
+        let files = PlaybackSource::Files(vec![
+            PlaylistFile {
+                path: "/res/it's [a]:b.mp3".to_string(),
+                gain_db: -2.5,
+            },
+            "/res/c.mp3".into(),
+        ]);
+        let (command, playlist_handle) = FfplayBackend::new("ffplay").command(&files, 0.0).unwrap();
+        assert!(playlist_handle.is_none());
+        assert_eq!(
+            args(&command),
+            [
+                "-autoexit",
+                "-nodisp",
+                "-f",
+                "lavfi",
+                "-i",
+                r"amovie=/res/it\\\'s \[a\]\\:b.mp3,volume=-2.5dB,aresample=48000,aformat=channel_layouts=stereo[a0];amovie=/res/c.mp3,volume=0dB,aresample=48000,aformat=channel_layouts=stereo[a1];[a0][a1]concat=n=2:v=0:a=1[out0]"
+            ]
+        );
+        assert_eq!(
+            args(&MpvBackend::new("mpv").command(
+                &files,
+                1.0,
+                std::path::Path::new("/tmp/mpv.sock")
+            ))[4..],
+            [
+                "--{",
+                "--af=lavfi=[volume=-1.5dB]",
+                "/res/it's [a]:b.mp3",
+                "--}",
+                "--{",
+                "--af=lavfi=[volume=1dB]",
+                "/res/c.mp3",
+                "--}"
+            ]
+        );
+    }
+
+    #[test]
+    fn mpv_volume_test() {
+        assert_eq!(mpv_volume(0.0), 100.0);
+        assert!((mpv_volume(-60.0) - 10.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn seek_target_test() {
+        assert_eq!("90".parse(), Ok(SeekTarget::Absolute(90.0)));
+        assert_eq!(" +10 ".parse(), Ok(SeekTarget::Relative(10.0)));
+        assert_eq!("-2.5".parse(), Ok(SeekTarget::Relative(-2.5)));
+        assert!("soon".parse::<SeekTarget>().is_err());
+    }
+}
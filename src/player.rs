@@ -1,207 +1,1333 @@
+use crate::playback_backend::{
+    PlaybackBackend, PlaybackPosition, PlaybackProcess, PlaybackSource, PlaylistFile,
+    PositionQuery, SeekTarget,
+};
+use crate::resource_catalogue::ResourceCatalogue;
+use crate::stream_resolver::StreamResolver;
+use chrono::{DateTime, Local};
 use log::*;
-use std::io::Write;
-use std::process::{Child, Command};
-use std::sync::Mutex;
-use tempfile::NamedTempFile;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex};
 
+#[allow(clippy::large_enum_variant)]
 enum PlayerState {
     Paused {},
     Playing {
+        playback_command: PlaybackCommand,
+        worker_process: Box<dyn PlaybackProcess>,
+        started_at: DateTime<Local>,
+        attempt: u32,
+        /// Backend keeps the process around, paused, until it's resumed.
+        suspended: bool,
+    },
+    Reconnecting {
+        playback_command: PlaybackCommand,
+        attempt: u32,
+        retry_at: DateTime<Local>,
+    },
+    Finished {
         description: String,
-        worker_process: Child,
-        #[allow(dead_code)]
-        playlist_handle: Option<NamedTempFile>,
+        exit_status: String,
+        finished_at: DateTime<Local>,
     },
 }
 struct PlaybackCommand {
-    command: Command,
+    source: PlaybackSource,
     description: String,
-    playlist_handle: Option<NamedTempFile>,
+    entries: Vec<String>,
+    /// Station link the url was resolved from, resolved again before reconnecting.
+    link: Option<String>,
+    /// Live streams get restarted when the worker process exits.
+    reconnect: bool,
+    gain_db: f64,
+}
+
+/// Where played content comes from, each category gets its own gain.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ContentSource {
+    /// `/play`, the queue and server files from the jukebox.
+    Radio,
+    Schedule,
+    Autogrzybke,
+    Benny,
+}
+
+/// Gain in dB the player program applies to content of each category, on top of the mixer
+/// volume. It only lasts as long as that playback, so nothing needs restoring afterwards.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SourceGains {
+    pub radio: f64,
+    pub schedule: f64,
+    pub autogrzybke: f64,
+    pub benny: f64,
+}
+
+impl SourceGains {
+    pub fn gain_db(&self, source: ContentSource) -> f64 {
+        match source {
+            ContentSource::Radio => self.radio,
+            ContentSource::Schedule => self.schedule,
+            ContentSource::Autogrzybke => self.autogrzybke,
+            ContentSource::Benny => self.benny,
+        }
+    }
+}
+
+/// How announcements from the scheduler and autogrzybke treat whatever is currently playing.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum InterruptionMode {
+    /// Stop current playback and restart it once the announcement is over.
+    Pause,
+    /// Play the announcement on top of current playback.
+    Overlay,
+    /// Like overlay, with current playback lowered by the duck gain until the announcement is
+    /// over. Needs a backend that can change the volume while playing, mpv.
+    Duck,
+}
+
+/// How much quieter current playback gets while an announcement plays in duck mode.
+pub const DEFAULT_DUCK_DB: f64 = -15.0;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct QueueEntry {
+    pub description: String,
+    pub entries: Vec<String>,
+}
+
+impl From<&PlaybackCommand> for QueueEntry {
+    fn from(playback_command: &PlaybackCommand) -> Self {
+        QueueEntry {
+            description: playback_command.description.clone(),
+            entries: playback_command.entries.clone(),
+        }
+    }
 }
 
 impl PlaybackCommand {
-    pub fn from_url(ffplay_path: &str, url: String, seek_pos: chrono::Duration) -> Self {
-        let start_time = chrono::NaiveTime::from_hms_opt(0, 0, 0).unwrap() + seek_pos;
-        info!("start_time: {}", start_time);
-        let mut command = Command::new(ffplay_path);
-        command
-            .arg("-autoexit")
-            .arg("-nodisp")
-            .arg("-fflags")
-            .arg("nobuffer")
-            .arg("-ss")
-            .arg(format!("{}", start_time))
-            .arg(url.clone());
+    pub fn from_url(url: String, seek_pos: chrono::Duration) -> Self {
         PlaybackCommand {
-            command: command,
-            description: url,
-            playlist_handle: None,
+            reconnect: url.starts_with("http://") || url.starts_with("https://"),
+            description: url.clone(),
+            entries: vec![url.clone()],
+            source: PlaybackSource::Url { url, seek_pos },
+            link: None,
+            gain_db: 0.0,
         }
     }
 
-    pub fn from_files(ffplay_path: &str, playlist: Vec<String>) -> Result<Self, std::io::Error> {
-        let mut playlist_file = tempfile::NamedTempFile::new()?;
-        for file in &playlist {
-            writeln!(playlist_file, "file {}", file)?;
+    pub fn from_files(playlist: Vec<PlaylistFile>) -> Self {
+        let entries: Vec<String> = playlist.iter().map(|file| file.path.clone()).collect();
+        PlaybackCommand {
+            description: entries.join(", "),
+            entries,
+            reconnect: false,
+            source: PlaybackSource::Files(playlist),
+            link: None,
+            gain_db: 0.0,
         }
-        playlist_file.flush()?;
-        let mut command = Command::new(ffplay_path);
-        command
-            .arg("-autoexit")
-            .arg("-nodisp")
-            .arg("-f")
-            .arg("concat")
-            .arg("-safe")
-            .arg("0")
-            .arg("-i")
-            .arg(playlist_file.path());
+    }
+
+    pub fn with_link(mut self, link: String) -> Self {
+        self.link = Some(link);
+        self
+    }
 
-        Ok(PlaybackCommand {
-            command: command,
-            description: playlist_file.path().to_string_lossy().to_string(),
-            playlist_handle: Some(playlist_file),
-        })
+    pub fn with_gain_db(mut self, gain_db: f64) -> Self {
+        self.gain_db = gain_db;
+        self
     }
 }
 
 impl PlayerState {
-    pub fn play(&mut self, mut playback_command: PlaybackCommand) -> Result<(), std::io::Error> {
+    pub fn play(
+        &mut self,
+        backend: &dyn PlaybackBackend,
+        playback_command: PlaybackCommand,
+    ) -> Result<(), std::io::Error> {
         info!("Play {}", playback_command.description);
 
         match self {
             PlayerState::Playing {
-                description: content_url,
+                playback_command: current,
+                suspended,
                 ..
             } => {
-                if *content_url == playback_command.description {
-                    info!("Already playing {}", *content_url);
-                    Ok(())
+                if current.source.is_same_content(&playback_command.source) {
+                    if *suspended {
+                        self.resume()
+                    } else {
+                        info!("Already playing {}", current.description);
+                        Ok(())
+                    }
                 } else {
                     self.pause()?;
-                    self.play(playback_command)
+                    self.play(backend, playback_command)
                 }
             }
-            PlayerState::Paused {} => {
-                info!("Restart raspotify.service to make sure audio card is available");
-                Command::new("sudo")
-                    .arg("systemctl")
-                    .arg("reset-failed")
-                    .arg("raspotify.service")
-                    .spawn()?;
-                    Command::new("sudo")
-                    .arg("systemctl")
-                    .arg("restart")
-                    .arg("raspotify.service")
-                    .spawn()?;
-                info!("Start playing {}", playback_command.description);
-                let spawn_result = playback_command.command.spawn()?;
+            _ => {
+                self.stop()?;
+                info!(
+                    "Start playing {} with {}",
+                    playback_command.description,
+                    backend.name()
+                );
+                let spawn_result =
+                    backend.start(&playback_command.source, playback_command.gain_db)?;
                 *self = PlayerState::Playing {
-                    description: playback_command.description,
+                    playback_command,
                     worker_process: spawn_result,
-                    playlist_handle: playback_command.playlist_handle,
+                    started_at: Local::now(),
+                    attempt: 0,
+                    suspended: false,
                 };
                 Ok(())
             }
         }
     }
 
-    pub fn toggle_play(&mut self, playback_command: PlaybackCommand) -> Result<(), std::io::Error> {
+    pub fn toggle_play(
+        &mut self,
+        backend: &dyn PlaybackBackend,
+        playback_command: PlaybackCommand,
+    ) -> Result<(), std::io::Error> {
         info!("Toggle {}", playback_command.description);
 
         match self {
             PlayerState::Playing {
-                description: content_url,
+                playback_command: current,
+                ..
+            }
+            | PlayerState::Reconnecting {
+                playback_command: current,
                 ..
             } => {
-                if *content_url == playback_command.description {
-                    info!("Already playing {}, pausing", *content_url);
+                if current.source.is_same_content(&playback_command.source) {
+                    info!("Already playing {}, pausing", current.description);
                     self.pause()
                 } else {
                     self.pause()?;
-                    self.play(playback_command)
+                    self.play(backend, playback_command)
                 }
             }
-            PlayerState::Paused {} => {
+            _ => {
                 info!(
                     "Not playing {}, start playback",
                     playback_command.description
                 );
-                self.play(playback_command)
+                self.play(backend, playback_command)
             }
         }
     }
 
     pub fn pause(&mut self) -> Result<(), std::io::Error> {
+        match self.stop()? {
+            Some(_) => Ok(()),
+            None => {
+                info!("Already Paused");
+                Ok(())
+            }
+        }
+    }
+
+    /// Pauses the worker process if the backend can do that, stops it otherwise.
+    pub fn suspend(&mut self) -> Result<(), std::io::Error> {
+        if let PlayerState::Playing {
+            playback_command,
+            worker_process,
+            suspended: suspended @ false,
+            ..
+        } = self
+        {
+            match worker_process.set_paused(true) {
+                Ok(()) => {
+                    info!("Suspend {}", playback_command.description);
+                    *suspended = true;
+                    return Ok(());
+                }
+                Err(e) if e.kind() == ErrorKind::Unsupported => {}
+                Err(e) => warn!(
+                    "Failed to suspend {}: {e}. Stopping it instead",
+                    playback_command.description
+                ),
+            }
+        }
+        self.pause()
+    }
+
+    pub fn resume(&mut self) -> Result<(), std::io::Error> {
         match self {
             PlayerState::Playing {
+                playback_command,
                 worker_process,
-                description,
+                suspended: suspended @ true,
                 ..
             } => {
-                info!("Pause {}", *description);
-                worker_process.kill()?;
-                let _ = worker_process.wait();
-                *self = PlayerState::Paused {};
+                info!("Resume {}", playback_command.description);
+                worker_process.set_paused(false)?;
+                *suspended = false;
                 Ok(())
             }
-            PlayerState::Paused {} => {
-                info!("Already Paused");
+            _ => Err(Error::new(ErrorKind::InvalidInput, "Nothing to resume")),
+        }
+    }
+
+    fn is_suspended(&self) -> bool {
+        matches!(
+            self,
+            PlayerState::Playing {
+                suspended: true,
+                ..
+            }
+        )
+    }
+
+    /// Runs a backend specific control operation on the current worker process.
+    fn control<T>(
+        &mut self,
+        operation: impl FnOnce(&mut dyn PlaybackProcess) -> Result<T, std::io::Error>,
+    ) -> Result<T, std::io::Error> {
+        match self {
+            PlayerState::Playing { worker_process, .. } => operation(worker_process.as_mut()),
+            _ => Err(Error::new(ErrorKind::InvalidInput, "Nothing is playing")),
+        }
+    }
+
+    /// Kills the worker process and hands back the command it was started with, so it can be
+    /// restarted later.
+    fn stop(&mut self) -> Result<Option<PlaybackCommand>, std::io::Error> {
+        if let PlayerState::Playing {
+            worker_process,
+            playback_command,
+            ..
+        } = self
+        {
+            info!("Pause {}", playback_command.description);
+            worker_process.kill()?;
+        }
+        match std::mem::replace(self, PlayerState::Paused {}) {
+            PlayerState::Playing {
+                playback_command, ..
+            }
+            | PlayerState::Reconnecting {
+                playback_command, ..
+            } => Ok(Some(playback_command)),
+            PlayerState::Paused {} | PlayerState::Finished { .. } => Ok(None),
+        }
+    }
+
+    /// Reaps the worker process and restarts dropped live streams. Returns true when playback
+    /// has just come to an end, so the next thing can be started.
+    pub fn supervise(
+        &mut self,
+        backend: &dyn PlaybackBackend,
+        policy: &ReconnectPolicy,
+    ) -> Result<bool, std::io::Error> {
+        match self {
+            PlayerState::Playing {
+                worker_process,
+                playback_command,
+                started_at,
+                attempt,
+                ..
+            } => {
+                let Some(exit_status) = worker_process.try_wait()? else {
+                    return Ok(false);
+                };
+                info!(
+                    "Finished {}, exit status: {}",
+                    playback_command.description, exit_status
+                );
+                let attempt = if Local::now() - *started_at > policy.stable_after {
+                    1
+                } else {
+                    *attempt + 1
+                };
+                if playback_command.reconnect && attempt <= policy.max_attempts {
+                    let retry_at = Local::now() + policy.delay(attempt);
+                    info!(
+                        "Reconnect {} at {}, attempt {}/{}",
+                        playback_command.description, retry_at, attempt, policy.max_attempts
+                    );
+                    let PlayerState::Playing {
+                        playback_command, ..
+                    } = std::mem::replace(self, PlayerState::Paused {})
+                    else {
+                        unreachable!()
+                    };
+                    *self = PlayerState::Reconnecting {
+                        playback_command,
+                        attempt,
+                        retry_at,
+                    };
+                    Ok(false)
+                } else {
+                    *self = PlayerState::Finished {
+                        description: playback_command.description.clone(),
+                        exit_status: exit_status.to_string(),
+                        finished_at: Local::now(),
+                    };
+                    Ok(true)
+                }
+            }
+            PlayerState::Reconnecting {
+                playback_command,
+                attempt,
+                retry_at,
+            } => {
+                if Local::now() < *retry_at {
+                    return Ok(false);
+                }
+                match backend.start(&playback_command.source, playback_command.gain_db) {
+                    Ok(worker_process) => {
+                        info!("Reconnected {}", playback_command.description);
+                        let attempt = *attempt;
+                        let PlayerState::Reconnecting {
+                            playback_command, ..
+                        } = std::mem::replace(self, PlayerState::Paused {})
+                        else {
+                            unreachable!()
+                        };
+                        *self = PlayerState::Playing {
+                            playback_command,
+                            worker_process,
+                            started_at: Local::now(),
+                            attempt,
+                            suspended: false,
+                        };
+                        Ok(false)
+                    }
+                    Err(e) if *attempt < policy.max_attempts => {
+                        *attempt += 1;
+                        *retry_at = Local::now() + policy.delay(*attempt);
+                        warn!(
+                            "Failed to reconnect {}: {e}. Retry at {}, attempt {}/{}",
+                            playback_command.description, retry_at, attempt, policy.max_attempts
+                        );
+                        Ok(false)
+                    }
+                    Err(e) => {
+                        error!(
+                            "Failed to reconnect {}: {e}. Giving up",
+                            playback_command.description
+                        );
+                        *self = PlayerState::Finished {
+                            description: playback_command.description.clone(),
+                            exit_status: e.to_string(),
+                            finished_at: Local::now(),
+                        };
+                        Ok(true)
+                    }
+                }
+            }
+            PlayerState::Paused {} | PlayerState::Finished { .. } => Ok(false),
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        matches!(
+            self,
+            PlayerState::Playing { .. } | PlayerState::Reconnecting { .. }
+        )
+    }
+
+    /// Link of the stream waiting to be reconnected, once it's time to.
+    fn link_due_for_reconnect(&self) -> Option<String> {
+        match self {
+            PlayerState::Reconnecting {
+                playback_command,
+                retry_at,
+                ..
+            } if Local::now() >= *retry_at => playback_command.link.clone(),
+            _ => None,
+        }
+    }
+
+    /// Reconnects the stream of the link to the url it resolves to now, unless something else
+    /// was started in the meantime.
+    fn set_reconnect_url(&mut self, link: &str, url: String) {
+        if let PlayerState::Reconnecting {
+            playback_command, ..
+        } = self
+        {
+            if playback_command.link.as_deref() != Some(link) {
+                return;
+            }
+            if let PlaybackSource::Url { url: current, .. } = &mut playback_command.source {
+                if *current != url {
+                    info!("Reconnect {link} to {url} instead of {current}");
+                    playback_command.description = url.clone();
+                    playback_command.entries = vec![url.clone()];
+                    *current = url;
+                }
+            }
+        }
+    }
+
+    /// Url of the live stream that is audible right now.
+    fn stream_url(&self) -> Option<String> {
+        match self {
+            PlayerState::Playing {
+                playback_command,
+                suspended: false,
+                ..
+            } if playback_command.reconnect => match &playback_command.source {
+                PlaybackSource::Url { url, .. } => Some(url.clone()),
+                PlaybackSource::Files(_) => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Query for the position of the worker process, with its pid.
+    fn position_query(&self) -> Option<(u32, PositionQuery)> {
+        match self {
+            PlayerState::Playing { worker_process, .. } => worker_process
+                .position_query()
+                .map(|query| (worker_process.id(), query)),
+            _ => None,
+        }
+    }
+
+    /// `position` is the last one the supervisor got, ignored if it's of another process.
+    fn status(&mut self, position: Option<&(u32, PlaybackPosition)>) -> PlaybackStatus {
+        match self {
+            PlayerState::Playing {
+                playback_command,
+                worker_process,
+                started_at,
+                suspended,
+                ..
+            } => {
+                let exit_status = worker_process
+                    .try_wait()
+                    .inspect_err(|e| error!("Failed to check worker process: {e}"))
+                    .ok()
+                    .flatten();
+                let position = match (exit_status, position) {
+                    (None, Some((pid, position))) if *pid == worker_process.id() => {
+                        Some(position.clone())
+                    }
+                    _ => None,
+                };
+                PlaybackStatus::Playing {
+                    description: playback_command.description.clone(),
+                    entries: playback_command.entries.clone(),
+                    started_at: *started_at,
+                    pid: worker_process.id(),
+                    suspended: *suspended,
+                    position,
+                    exited: exit_status.is_some(),
+                    exit_status: exit_status.map(|s| s.to_string()),
+                }
+            }
+            PlayerState::Reconnecting {
+                playback_command,
+                attempt,
+                retry_at,
+            } => PlaybackStatus::Reconnecting {
+                description: playback_command.description.clone(),
+                entries: playback_command.entries.clone(),
+                attempt: *attempt,
+                retry_at: *retry_at,
+            },
+            PlayerState::Finished {
+                description,
+                exit_status,
+                finished_at,
+            } => PlaybackStatus::Finished {
+                description: description.clone(),
+                exit_status: exit_status.clone(),
+                finished_at: *finished_at,
+            },
+            PlayerState::Paused {} => PlaybackStatus::Paused,
+        }
+    }
+}
+
+/// Decides whether and when a live stream whose worker process exited gets restarted.
+pub struct ReconnectPolicy {
+    pub max_attempts: u32,
+    pub base_delay: chrono::Duration,
+    /// Streams which played at least that long start counting attempts from scratch.
+    pub stable_after: chrono::Duration,
+}
+
+impl ReconnectPolicy {
+    fn delay(&self, attempt: u32) -> chrono::Duration {
+        let max_delay = chrono::Duration::seconds(60);
+        self.base_delay
+            .checked_mul(2_i32.pow(attempt.saturating_sub(1).min(16)))
+            .unwrap_or(max_delay)
+            .min(max_delay)
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "state")]
+pub enum PlaybackStatus {
+    Paused,
+    Playing {
+        description: String,
+        entries: Vec<String>,
+        started_at: DateTime<Local>,
+        pid: u32,
+        suspended: bool,
+        position: Option<PlaybackPosition>,
+        /// The worker process is gone, but the queue task has not picked it up yet.
+        exited: bool,
+        exit_status: Option<String>,
+    },
+    Reconnecting {
+        description: String,
+        entries: Vec<String>,
+        attempt: u32,
+        retry_at: DateTime<Local>,
+    },
+    Finished {
+        description: String,
+        exit_status: String,
+        finished_at: DateTime<Local>,
+    },
+}
+
+#[derive(Serialize, Debug)]
+pub struct PlayerStatus {
+    #[serde(flatten)]
+    pub playback: PlaybackStatus,
+    pub queue_length: usize,
+    pub interrupted: Option<String>,
+}
+
+struct PlayerImpl {
+    backend: Box<dyn PlaybackBackend>,
+    state: PlayerState,
+    queue: VecDeque<PlaybackCommand>,
+    interrupted: Option<PlaybackCommand>,
+    overlays: Vec<(PlaybackCommand, Box<dyn PlaybackProcess>)>,
+    /// Current playback is lowered until the overlays are over.
+    ducked: bool,
+    /// Last position reported by the worker process with that pid.
+    position: Option<(u32, PlaybackPosition)>,
+}
+
+impl PlayerImpl {
+    fn new(backend: Box<dyn PlaybackBackend>) -> Self {
+        PlayerImpl {
+            backend,
+            state: PlayerState::Paused {},
+            queue: VecDeque::new(),
+            interrupted: None,
+            overlays: Vec::new(),
+            ducked: false,
+            position: None,
+        }
+    }
+
+    /// Explicit user action replaces whatever an announcement was about to resume.
+    fn forget_interrupted(&mut self) {
+        if let Some(interrupted) = self.interrupted.take() {
+            info!("Will not resume {}", interrupted.description);
+        }
+    }
+
+    fn stop_overlays(&mut self) {
+        for (playback_command, mut worker_process) in self.overlays.drain(..) {
+            info!("Stop {}", playback_command.description);
+            let _ = worker_process.kill();
+        }
+    }
+
+    fn play(&mut self, playback_command: PlaybackCommand) -> Result<(), std::io::Error> {
+        self.state.play(self.backend.as_ref(), playback_command)
+    }
+
+    fn toggle_play(&mut self, playback_command: PlaybackCommand) -> Result<(), std::io::Error> {
+        self.state
+            .toggle_play(self.backend.as_ref(), playback_command)
+    }
+
+    fn interrupt(
+        &mut self,
+        announcement: PlaybackCommand,
+        mode: InterruptionMode,
+        duck_db: f64,
+    ) -> Result<(), std::io::Error> {
+        match mode {
+            InterruptionMode::Pause => {
+                let was_suspended = self.state.is_suspended();
+                if let Some(stopped) = self.state.stop()? {
+                    // When an announcement interrupts another one, the original playback is
+                    // already stashed. Playback paused by the user stays off.
+                    if self.interrupted.is_none() && !was_suspended {
+                        info!("Interrupt {}", stopped.description);
+                        self.interrupted = Some(stopped);
+                    }
+                }
+                self.play(announcement)
+            }
+            InterruptionMode::Overlay => self.overlay(announcement),
+            InterruptionMode::Duck => {
+                self.overlay(announcement)?;
+                if !self.ducked && self.state.is_playing() {
+                    match self.state.control(|process| process.set_volume_db(duck_db)) {
+                        Ok(()) => {
+                            info!("Duck current playback by {duck_db}dB");
+                            self.ducked = true;
+                        }
+                        Err(e) => warn!("Failed to duck current playback: {e}"),
+                    }
+                }
                 Ok(())
             }
         }
     }
+
+    fn overlay(&mut self, announcement: PlaybackCommand) -> Result<(), std::io::Error> {
+        info!(
+            "Play {} on top of current playback",
+            announcement.description
+        );
+        let worker_process = self
+            .backend
+            .start(&announcement.source, announcement.gain_db)?;
+        self.overlays.push((announcement, worker_process));
+        Ok(())
+    }
+
+    /// Puts back the volume of current playback once no announcement plays on top of it.
+    fn restore_ducked(&mut self) {
+        if !self.ducked || !self.overlays.is_empty() {
+            return;
+        }
+        self.ducked = false;
+        if self.state.is_playing() {
+            info!("Restore volume of current playback");
+            if let Err(e) = self.state.control(|process| process.set_volume_db(0.0)) {
+                warn!("Failed to restore volume of current playback: {e}");
+            }
+        }
+    }
+
+    fn play_next(&mut self) -> Result<(), std::io::Error> {
+        match self.queue.pop_front() {
+            Some(playback_command) => {
+                info!("Queue: {} entries left", self.queue.len());
+                self.play(playback_command)
+            }
+            None => {
+                info!("Queue is empty");
+                self.state.pause()
+            }
+        }
+    }
+
+    fn enqueue(&mut self, playback_command: PlaybackCommand) -> Result<usize, std::io::Error> {
+        info!("Enqueue {}", playback_command.description);
+        self.queue.push_back(playback_command);
+        if !self.state.is_playing() {
+            self.play_next()?;
+        }
+        Ok(self.queue.len())
+    }
+
+    fn remove(&mut self, index: usize) -> Result<(), std::io::Error> {
+        let removed = self.queue.remove(index).ok_or(queue_index_error(index))?;
+        info!("Removed {} from queue", removed.description);
+        Ok(())
+    }
+
+    fn reorder(&mut self, from: usize, to: usize) -> Result<(), std::io::Error> {
+        if to >= self.queue.len() {
+            return Err(queue_index_error(to));
+        }
+        let moved = self.queue.remove(from).ok_or(queue_index_error(from))?;
+        info!(
+            "Move {} in queue from {} to {}",
+            moved.description, from, to
+        );
+        self.queue.insert(to, moved);
+        Ok(())
+    }
+
+    fn supervise(&mut self, policy: &ReconnectPolicy) -> Result<(), std::io::Error> {
+        self.overlays
+            .retain_mut(
+                |(playback_command, worker_process)| match worker_process.try_wait() {
+                    Ok(Some(exit_status)) => {
+                        info!(
+                            "Finished {}, exit status: {}",
+                            playback_command.description, exit_status
+                        );
+                        false
+                    }
+                    Ok(None) => true,
+                    Err(e) => {
+                        error!("Failed to wait for {}: {e}", playback_command.description);
+                        false
+                    }
+                },
+            );
+        self.restore_ducked();
+        if self.state.supervise(self.backend.as_ref(), policy)? {
+            if let Some(interrupted) = self.interrupted.take() {
+                info!("Resume {}", interrupted.description);
+                self.play(interrupted)?;
+            } else if !self.queue.is_empty() {
+                self.play_next()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn queue_index_error(index: usize) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("No entry at position {index} in queue"),
+    )
 }
 
 pub struct Player {
-    state: Mutex<PlayerState>,
-    ffplay_path: String,
+    player_impl: Mutex<PlayerImpl>,
+    interruption_mode: InterruptionMode,
+    duck_db: f64,
+    reconnect_policy: ReconnectPolicy,
+    source_gains: SourceGains,
+    resources: Arc<ResourceCatalogue>,
 }
 
 impl Player {
-    pub fn new(ffplay_path: &str) -> Player {
+    pub fn new(
+        backend: Box<dyn PlaybackBackend>,
+        interruption_mode: InterruptionMode,
+        reconnect_policy: ReconnectPolicy,
+        source_gains: SourceGains,
+        resources: Arc<ResourceCatalogue>,
+    ) -> Player {
         Player {
-            state: Mutex::new(PlayerState::Paused {}),
-            ffplay_path: ffplay_path.to_string(),
+            player_impl: Mutex::new(PlayerImpl::new(backend)),
+            interruption_mode,
+            duck_db: DEFAULT_DUCK_DB,
+            reconnect_policy,
+            source_gains,
+            resources,
         }
     }
+
+    /// Gain in dB of current playback while an announcement plays in duck mode.
+    pub fn with_duck_db(mut self, duck_db: f64) -> Self {
+        self.duck_db = duck_db;
+        self
+    }
+
+    /// Attaches the loudness normalizing gain of each file.
+    fn playlist_files(&self, playlist: Vec<String>) -> Vec<PlaylistFile> {
+        playlist
+            .into_iter()
+            .map(|path| PlaylistFile {
+                gain_db: self.resources.loudness_gain_db(&path),
+                path,
+            })
+            .collect()
+    }
 }
 
 impl Player {
+    /// `new_content_url` is what `link` resolved to.
     pub fn play(
         &self,
+        link: String,
         new_content_url: String,
         seek_pos: chrono::Duration,
     ) -> Result<(), std::io::Error> {
-        self.state.lock().unwrap().play(PlaybackCommand::from_url(
-            self.ffplay_path.as_str(),
-            new_content_url,
-            seek_pos,
-        ))
+        let mut player_impl = self.player_impl.lock().unwrap();
+        player_impl.forget_interrupted();
+        player_impl.play(
+            PlaybackCommand::from_url(new_content_url, seek_pos)
+                .with_link(link)
+                .with_gain_db(self.source_gains.gain_db(ContentSource::Radio)),
+        )
     }
 
     pub fn toggle_play(
         &self,
         new_content_url: String,
         seek_pos: chrono::Duration,
+        source: ContentSource,
     ) -> Result<(), std::io::Error> {
-        self.state
+        let mut player_impl = self.player_impl.lock().unwrap();
+        player_impl.forget_interrupted();
+        player_impl.toggle_play(
+            PlaybackCommand::from_url(new_content_url, seek_pos)
+                .with_gain_db(self.source_gains.gain_db(source)),
+        )
+    }
+
+    pub fn play_local_playlist(&self, playlist: Vec<String>) -> Result<(), std::io::Error> {
+        let mut player_impl = self.player_impl.lock().unwrap();
+        player_impl.forget_interrupted();
+        player_impl.play(
+            PlaybackCommand::from_files(self.playlist_files(playlist))
+                .with_gain_db(self.source_gains.gain_db(ContentSource::Radio)),
+        )
+    }
+
+    /// Plays an announcement according to the configured `InterruptionMode`. In pause mode
+    /// whatever was playing before gets restarted once the announcement is over, in duck mode
+    /// its volume is put back.
+    pub fn interrupt_with_local_playlist(
+        &self,
+        playlist: Vec<String>,
+        source: ContentSource,
+    ) -> Result<(), std::io::Error> {
+        self.player_impl.lock().unwrap().interrupt(
+            PlaybackCommand::from_files(self.playlist_files(playlist))
+                .with_gain_db(self.source_gains.gain_db(source)),
+            self.interruption_mode,
+            self.duck_db,
+        )
+    }
+
+    pub fn pause(&self) -> Result<(), std::io::Error> {
+        let mut player_impl = self.player_impl.lock().unwrap();
+        player_impl.forget_interrupted();
+        player_impl.stop_overlays();
+        player_impl.state.suspend()
+    }
+
+    pub fn resume(&self) -> Result<(), std::io::Error> {
+        self.player_impl.lock().unwrap().state.resume()
+    }
+
+    pub fn seek(&self, target: SeekTarget) -> Result<(), std::io::Error> {
+        info!("Seek {target:?}");
+        self.player_impl
+            .lock()
+            .unwrap()
+            .state
+            .control(|process| process.seek(target))
+    }
+
+    pub fn playlist_next(&self) -> Result<(), std::io::Error> {
+        self.player_impl
+            .lock()
+            .unwrap()
+            .state
+            .control(|process| process.playlist_next())
+    }
+
+    pub fn playlist_prev(&self) -> Result<(), std::io::Error> {
+        self.player_impl
             .lock()
             .unwrap()
-            .toggle_play(PlaybackCommand::from_url(
-                self.ffplay_path.as_str(),
-                new_content_url,
-                seek_pos,
+            .state
+            .control(|process| process.playlist_prev())
+    }
+
+    /// Appends url, resolved from `link`, to the queue, starts playback right away if nothing
+    /// is playing. Returns the number of entries waiting in the queue.
+    pub fn enqueue(&self, link: String, new_content_url: String) -> Result<usize, std::io::Error> {
+        self.player_impl.lock().unwrap().enqueue(
+            PlaybackCommand::from_url(new_content_url, chrono::Duration::seconds(0))
+                .with_link(link)
+                .with_gain_db(self.source_gains.gain_db(ContentSource::Radio)),
+        )
+    }
+
+    pub fn enqueue_local_playlist(&self, playlist: Vec<String>) -> Result<usize, std::io::Error> {
+        self.player_impl.lock().unwrap().enqueue(
+            PlaybackCommand::from_files(self.playlist_files(playlist))
+                .with_gain_db(self.source_gains.gain_db(ContentSource::Radio)),
+        )
+    }
+
+    pub fn dequeue(&self, index: usize) -> Result<(), std::io::Error> {
+        self.player_impl.lock().unwrap().remove(index)
+    }
+
+    pub fn reorder_queue(&self, from: usize, to: usize) -> Result<(), std::io::Error> {
+        self.player_impl.lock().unwrap().reorder(from, to)
+    }
+
+    pub fn skip(&self) -> Result<(), std::io::Error> {
+        info!("Skip");
+        let mut player_impl = self.player_impl.lock().unwrap();
+        player_impl.forget_interrupted();
+        player_impl.play_next()
+    }
+
+    pub fn clear_queue(&self) {
+        info!("Clear queue");
+        self.player_impl.lock().unwrap().queue.clear();
+    }
+
+    pub fn status(&self) -> PlayerStatus {
+        let player_impl = &mut *self.player_impl.lock().unwrap();
+        PlayerStatus {
+            playback: player_impl.state.status(player_impl.position.as_ref()),
+            queue_length: player_impl.queue.len(),
+            interrupted: player_impl
+                .interrupted
+                .as_ref()
+                .map(|c| c.description.clone()),
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.player_impl.lock().unwrap().state.is_playing()
+    }
+
+    pub fn current_stream_url(&self) -> Option<String> {
+        self.player_impl.lock().unwrap().state.stream_url()
+    }
+
+    pub fn get_queue(&self) -> Vec<QueueEntry> {
+        self.player_impl
+            .lock()
+            .unwrap()
+            .queue
+            .iter()
+            .map(QueueEntry::from)
+            .collect()
+    }
+
+    /// Watches the worker process: reconnects dropped streams, resumes interrupted playback
+    /// and advances the queue.
+    pub async fn run_supervisor(&self, stream_resolver: Arc<StreamResolver>) {
+        let mut interval = tokio::time::interval(std::time::Duration::from_millis(500));
+        info!("Running player supervisor");
+        loop {
+            self.resolve_reconnect_link(&stream_resolver).await;
+            self.player_impl
+                .lock()
+                .unwrap()
+                .supervise(&self.reconnect_policy)
+                .unwrap_or_else(|e| error!("Failed to supervise player: {e}"));
+            self.update_position().await;
+            interval.tick().await;
+        }
+    }
+
+    /// The url a station link resolved to may be what went down, e.g. the first mirror of a
+    /// playlist. Links are resolved again right before their streams are reconnected, without
+    /// the lock. The last url is used again if that fails.
+    async fn resolve_reconnect_link(&self, stream_resolver: &StreamResolver) {
+        let Some(link) = self
+            .player_impl
+            .lock()
+            .unwrap()
+            .state
+            .link_due_for_reconnect()
+        else {
+            return;
+        };
+        match stream_resolver.resolve_again(&link).await {
+            Ok(url) => self
+                .player_impl
+                .lock()
+                .unwrap()
+                .state
+                .set_reconnect_url(&link, url),
+            Err(e) => warn!("Failed to resolve {link} again: {e:#}"),
+        }
+    }
+
+    /// Asks the worker process where it is for `status`. The query runs without the lock, a
+    /// player program slow to answer doesn't hold up the requests.
+    async fn update_position(&self) {
+        let query = self.player_impl.lock().unwrap().state.position_query();
+        let position = match query {
+            Some((pid, query)) => match tokio::task::spawn_blocking(query).await {
+                Ok(Ok(position)) => position.map(|position| (pid, position)),
+                Ok(Err(e)) => {
+                    debug!("Failed to get playback position: {e}");
+                    None
+                }
+                Err(e) => {
+                    error!("Failed to get playback position: {e}");
+                    None
+                }
+            },
+            None => None,
+        };
+        self.player_impl.lock().unwrap().position = position;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::recording_backend::{RecordedPlayback, RecordingBackend};
+
+    fn player_impl_with_queue(urls: &[&str]) -> PlayerImpl {
+        PlayerImpl {
+            queue: urls
+                .iter()
+                .map(|url| PlaybackCommand::from_url(url.to_string(), chrono::Duration::zero()))
+                .collect(),
+            ..PlayerImpl::new(Box::new(RecordingBackend::default()))
+        }
+    }
+
+    fn queued_urls(player_impl: &PlayerImpl) -> Vec<String> {
+        player_impl
+            .queue
+            .iter()
+            .map(|c| c.description.clone())
+            .collect()
+    }
+
+    fn test_reconnect_policy() -> ReconnectPolicy {
+        ReconnectPolicy {
+            max_attempts: 3,
+            base_delay: chrono::Duration::seconds(2),
+            stable_after: chrono::Duration::seconds(60),
+        }
+    }
+
+    #[test]
+    fn reconnect_delay_test() {
+        let policy = test_reconnect_policy();
+        assert_eq!(policy.delay(1), chrono::Duration::seconds(2));
+        assert_eq!(policy.delay(2), chrono::Duration::seconds(4));
+        assert_eq!(policy.delay(3), chrono::Duration::seconds(8));
+        assert_eq!(policy.delay(10), chrono::Duration::seconds(60));
+        assert_eq!(policy.delay(u32::MAX), chrono::Duration::seconds(60));
+        let slow = ReconnectPolicy {
+            base_delay: chrono::Duration::MAX,
+            ..policy
+        };
+        assert_eq!(slow.delay(2), chrono::Duration::seconds(60));
+    }
+
+    #[test]
+    fn reorder_and_remove_test() {
+        let mut player_impl = player_impl_with_queue(&["a", "b", "c"]);
+        player_impl.reorder(2, 0).unwrap();
+        assert_eq!(queued_urls(&player_impl), ["c", "a", "b"]);
+        player_impl.reorder(0, 2).unwrap();
+        assert_eq!(queued_urls(&player_impl), ["a", "b", "c"]);
+        assert!(player_impl.reorder(0, 3).is_err());
+        assert!(player_impl.reorder(3, 0).is_err());
+        player_impl.remove(1).unwrap();
+        assert_eq!(queued_urls(&player_impl), ["a", "c"]);
+        assert!(player_impl.remove(2).is_err());
+    }
+
+    #[test]
+    fn paused_status_serialization_test() {
+        let player = Player::new(
+            Box::new(RecordingBackend::default()),
+            InterruptionMode::Pause,
+            test_reconnect_policy(),
+            SourceGains::default(),
+            Arc::new(ResourceCatalogue::default()),
+        );
+        assert_eq!(
+            serde_json::to_string(&player.status()).unwrap(),
+            r#"{"state":"Paused","queue_length":0,"interrupted":null}"#
+        );
+    }
+
+    #[test]
+    fn overlay_announcement_test() {
+        let backend = RecordingBackend::default();
+        let mut player_impl = PlayerImpl::new(Box::new(backend.clone()));
+        let announcement: Vec<PlaylistFile> = vec!["/noise.mp3".into()];
+        player_impl
+            .interrupt(
+                PlaybackCommand::from_files(announcement.clone()).with_gain_db(-6.0),
+                InterruptionMode::Overlay,
+                DEFAULT_DUCK_DB,
+            )
+            .unwrap();
+        assert_eq!(
+            backend.recorded(),
+            [RecordedPlayback {
+                source: PlaybackSource::Files(announcement),
+                gain_db: -6.0,
+                volume_db: 0.0,
+                killed: false,
+                finished: false,
+            }]
+        );
+        assert_eq!(player_impl.overlays.len(), 1);
+        player_impl.supervise(&test_reconnect_policy()).unwrap();
+        assert_eq!(player_impl.overlays.len(), 1);
+        backend.finish_last();
+        player_impl.supervise(&test_reconnect_policy()).unwrap();
+        assert!(player_impl.overlays.is_empty());
+        assert!(!player_impl.state.is_playing());
+    }
+
+    #[test]
+    fn duck_announcement_test() {
+        let backend = RecordingBackend::default();
+        let mut player_impl = PlayerImpl::new(Box::new(backend.clone()));
+        player_impl
+            .play(PlaybackCommand::from_url(
+                "http://radio".to_string(),
+                chrono::Duration::zero(),
             ))
+            .unwrap();
+        for _ in 0..2 {
+            player_impl
+                .interrupt(
+                    PlaybackCommand::from_files(vec!["/noise.mp3".into()]),
+                    InterruptionMode::Duck,
+                    -20.0,
+                )
+                .unwrap();
+        }
+        let volumes = || {
+            backend
+                .recorded()
+                .iter()
+                .map(|recorded| recorded.volume_db)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(volumes(), [-20.0, 0.0, 0.0]);
+
+        // Stays lowered until the last announcement is over.
+        backend.finish_last();
+        player_impl.supervise(&test_reconnect_policy()).unwrap();
+        assert_eq!(volumes(), [-20.0, 0.0, 0.0]);
+        player_impl.stop_overlays();
+        player_impl.supervise(&test_reconnect_policy()).unwrap();
+        assert_eq!(volumes(), [0.0, 0.0, 0.0]);
+        assert!(!backend.recorded()[0].killed);
+        assert!(player_impl.state.is_playing());
     }
 
-    pub fn play_local_playlist(&self, playlist: Vec<String>) -> Result<(), std::io::Error> {
-        self.state.lock().unwrap().play(PlaybackCommand::from_files(
-            self.ffplay_path.as_str(),
-            playlist,
-        )?)
+    #[test]
+    fn interrupt_and_resume_test() {
+        let backend = RecordingBackend::default();
+        let mut player_impl = PlayerImpl::new(Box::new(backend.clone()));
+        let radio = PlaybackCommand::from_url("http://radio".to_string(), chrono::Duration::zero());
+        let radio_source = radio.source.clone();
+        player_impl.play(radio).unwrap();
+        player_impl
+            .interrupt(
+                PlaybackCommand::from_files(vec!["/noise.mp3".into()]),
+                InterruptionMode::Pause,
+                DEFAULT_DUCK_DB,
+            )
+            .unwrap();
+        assert!(backend.recorded()[0].killed);
+        assert_eq!(
+            player_impl.interrupted.as_ref().unwrap().source,
+            radio_source
+        );
+
+        backend.finish_last();
+        player_impl.supervise(&test_reconnect_policy()).unwrap();
+        let recorded = backend.recorded();
+        assert_eq!(recorded.len(), 3);
+        assert_eq!(recorded[2].source, radio_source);
+        assert!(player_impl.interrupted.is_none());
+        assert!(player_impl.state.is_playing());
     }
 
-    pub fn pause(&self) -> Result<(), std::io::Error> {
-        self.state.lock().unwrap().pause()
+    #[test]
+    fn queue_advances_when_playback_finishes_test() {
+        let backend = RecordingBackend::default();
+        let mut player_impl = PlayerImpl::new(Box::new(backend.clone()));
+        player_impl
+            .enqueue(PlaybackCommand::from_files(vec!["/a.mp3".into()]))
+            .unwrap();
+        player_impl
+            .enqueue(PlaybackCommand::from_files(vec!["/b.mp3".into()]))
+            .unwrap();
+        assert_eq!(backend.recorded().len(), 1);
+        assert_eq!(player_impl.queue.len(), 1);
+
+        backend.finish_last();
+        player_impl.supervise(&test_reconnect_policy()).unwrap();
+        assert_eq!(
+            backend.recorded()[1].source,
+            PlaybackSource::Files(vec!["/b.mp3".into()])
+        );
+        assert!(player_impl.queue.is_empty());
+
+        backend.finish_last();
+        player_impl.supervise(&test_reconnect_policy()).unwrap();
+        assert!(matches!(player_impl.state, PlayerState::Finished { .. }));
+    }
+
+    #[tokio::test]
+    async fn position_is_of_the_current_process_test() {
+        let player = Player::new(
+            Box::new(RecordingBackend::default()),
+            InterruptionMode::Pause,
+            test_reconnect_policy(),
+            SourceGains::default(),
+            Arc::new(ResourceCatalogue::default()),
+        );
+        let position = |player: &Player| match player.status().playback {
+            PlaybackStatus::Playing { position, .. } => position,
+            status => panic!("Not playing: {status:?}"),
+        };
+        player
+            .play_local_playlist(vec!["/a.mp3".to_string()])
+            .unwrap();
+        assert_eq!(position(&player), None);
+        player.update_position().await;
+        assert_eq!(position(&player).unwrap().playlist_pos, Some(0));
+
+        player
+            .play_local_playlist(vec!["/b.mp3".to_string()])
+            .unwrap();
+        assert_eq!(position(&player), None);
+    }
+
+    #[tokio::test]
+    async fn reconnect_resolves_link_again_test() {
+        let backend = RecordingBackend::default();
+        let player = Player::new(
+            Box::new(backend.clone()),
+            InterruptionMode::Pause,
+            ReconnectPolicy {
+                base_delay: chrono::Duration::zero(),
+                ..test_reconnect_policy()
+            },
+            SourceGains::default(),
+            Arc::new(ResourceCatalogue::default()),
+        );
+        // Not http, so it resolves to itself without asking anyone.
+        player
+            .play(
+                "/radio.pls".to_string(),
+                "http://dead-mirror/stream".to_string(),
+                chrono::Duration::zero(),
+            )
+            .unwrap();
+        backend.finish_last();
+        let supervise = || {
+            player
+                .player_impl
+                .lock()
+                .unwrap()
+                .supervise(&player.reconnect_policy)
+                .unwrap()
+        };
+        supervise();
+        assert!(matches!(
+            player.player_impl.lock().unwrap().state,
+            PlayerState::Reconnecting { .. }
+        ));
+
+        player
+            .resolve_reconnect_link(&StreamResolver::new().unwrap())
+            .await;
+        supervise();
+        assert_eq!(
+            backend.recorded()[1].source,
+            PlaybackSource::Url {
+                url: "/radio.pls".to_string(),
+                seek_pos: chrono::Duration::zero(),
+            }
+        );
     }
 }
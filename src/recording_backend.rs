@@ -0,0 +1,104 @@
+use crate::playback_backend::{
+    PlaybackBackend, PlaybackPosition, PlaybackProcess, PlaybackSource, PositionQuery,
+};
+use log::*;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordedPlayback {
+    pub source: PlaybackSource,
+    pub gain_db: f64,
+    /// Set with `set_volume_db` while playing.
+    pub volume_db: f64,
+    pub killed: bool,
+    pub finished: bool,
+}
+
+/// Keeps track of what would have been played instead of playing it.
+#[derive(Clone, Default)]
+pub struct RecordingBackend {
+    recorded: Arc<Mutex<Vec<RecordedPlayback>>>,
+}
+
+struct RecordingProcess {
+    recorded: Arc<Mutex<Vec<RecordedPlayback>>>,
+    index: usize,
+}
+
+impl RecordingBackend {
+    pub fn recorded(&self) -> Vec<RecordedPlayback> {
+        self.recorded.lock().unwrap().clone()
+    }
+
+    /// Makes the most recently started playback end on its own.
+    pub fn finish_last(&self) {
+        if let Some(last) = self.recorded.lock().unwrap().last_mut() {
+            last.finished = true;
+        }
+    }
+}
+
+impl PlaybackBackend for RecordingBackend {
+    fn name(&self) -> &'static str {
+        "recording"
+    }
+
+    fn start(
+        &self,
+        source: &PlaybackSource,
+        gain_db: f64,
+    ) -> Result<Box<dyn PlaybackProcess>, std::io::Error> {
+        info!("Recording playback of {source:?} at {gain_db}dB");
+        let mut recorded = self.recorded.lock().unwrap();
+        recorded.push(RecordedPlayback {
+            source: source.clone(),
+            gain_db,
+            volume_db: 0.0,
+            killed: false,
+            finished: false,
+        });
+        Ok(Box::new(RecordingProcess {
+            recorded: self.recorded.clone(),
+            index: recorded.len() - 1,
+        }))
+    }
+}
+
+impl PlaybackProcess for RecordingProcess {
+    fn id(&self) -> u32 {
+        self.index as u32
+    }
+
+    fn try_wait(&mut self) -> Result<Option<ExitStatus>, std::io::Error> {
+        let recorded = &self.recorded.lock().unwrap()[self.index];
+        if recorded.finished || recorded.killed {
+            Ok(Some(ExitStatus::from_raw(0)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn kill(&mut self) -> Result<(), std::io::Error> {
+        self.recorded.lock().unwrap()[self.index].killed = true;
+        Ok(())
+    }
+
+    fn set_volume_db(&mut self, volume_db: f64) -> Result<(), std::io::Error> {
+        self.recorded.lock().unwrap()[self.index].volume_db = volume_db;
+        Ok(())
+    }
+
+    /// Always at the start of the first entry.
+    fn position_query(&self) -> Option<PositionQuery> {
+        Some(Box::new(|| {
+            Ok(Some(PlaybackPosition {
+                position_secs: 0.0,
+                duration_secs: None,
+                playlist_pos: Some(0),
+                playlist_count: None,
+            }))
+        }))
+    }
+}
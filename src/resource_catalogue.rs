@@ -1,13 +1,25 @@
 use std::collections::{BTreeSet, HashMap};
 use std::fs::canonicalize;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
 use anyhow::{Context as _, Result};
 use log::{info, warn};
 use rand::seq::IndexedRandom as _;
 
+use crate::loudness::{AnalyzerUnavailable, LoudnessAnalyzer};
+
+/// Loudness normalization never changes a file by more than this, so silence or a broken
+/// measurement can't blow up the speakers.
+const MAX_LOUDNESS_GAIN_DB: f64 = 12.0;
+
 #[derive(Default)]
-pub struct ResourceCatalogue(HashMap<String, Vec<PathBuf>>, String);
+pub struct ResourceCatalogue {
+    files: HashMap<String, Vec<PathBuf>>,
+    joined_list_of_files: String,
+    /// Filled in by `analyze_loudness`, files not analyzed yet play unchanged.
+    loudness_gains: Mutex<HashMap<PathBuf, f64>>,
+}
 
 impl ResourceCatalogue {
     pub fn try_from_dir_path(path: impl AsRef<Path>) -> Result<Self> {
@@ -40,19 +52,61 @@ impl ResourceCatalogue {
             .into_iter()
             .flatten()
             .fold("".to_string(), |acc, p| acc + &p.to_string_lossy() + "\n");
-        Ok(Self(catalogue, joined_list_of_files))
+        Ok(Self {
+            files: catalogue,
+            joined_list_of_files,
+            loudness_gains: Mutex::new(HashMap::new()),
+        })
     }
 
     pub fn random_sample(&self, basename: &str) -> Option<String> {
         let mut rng = rand::rng();
-        self.0
+        self.files
             .get(&basename.to_lowercase())
             .and_then(|matching_files| matching_files.choose(&mut rng))
             .map(|p| p.to_string_lossy().into())
     }
 
     pub fn get_joned_list_of_files(&self) -> &str {
-        self.1.as_str()
+        self.joined_list_of_files.as_str()
+    }
+
+    /// Measures every file and stores the gain bringing it to `target_lufs`. Slow on the first
+    /// run, meant for a blocking thread.
+    pub fn analyze_loudness(&self, analyzer: &mut LoudnessAnalyzer, target_lufs: f64) {
+        let files = self.files.values().flatten().collect::<BTreeSet<_>>();
+        info!("Analyzing loudness of {} files", files.len());
+        for file in files {
+            match analyzer.integrated_loudness(file) {
+                Ok(integrated_lufs) => {
+                    let gain_db = (target_lufs - integrated_lufs)
+                        .clamp(-MAX_LOUDNESS_GAIN_DB, MAX_LOUDNESS_GAIN_DB);
+                    self.loudness_gains
+                        .lock()
+                        .unwrap()
+                        .insert(file.clone(), gain_db);
+                }
+                Err(e) if e.downcast_ref::<AnalyzerUnavailable>().is_some() => {
+                    warn!("Loudness analysis disabled: {e:#}");
+                    return;
+                }
+                Err(e) => warn!("Failed to analyze loudness: {e:#}"),
+            }
+        }
+        if let Err(e) = analyzer.save() {
+            warn!("Failed to save loudness cache: {e:#}");
+        }
+        info!("Loudness analysis done");
+    }
+
+    /// Gain in dB normalizing the file's loudness, 0 if unknown.
+    pub fn loudness_gain_db(&self, file: &str) -> f64 {
+        self.loudness_gains
+            .lock()
+            .unwrap()
+            .get(Path::new(file))
+            .copied()
+            .unwrap_or(0.0)
     }
 }
 
@@ -64,7 +118,7 @@ fn key_from_path(path: impl AsRef<Path>, base: impl AsRef<Path>) -> Option<Strin
         .trim_end_matches('.')
         .trim_end_matches(char::is_numeric)
         .to_lowercase();
-    Some(String::from(result))
+    Some(result)
 }
 
 pub fn list_files_recursive(dir: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
@@ -105,4 +159,40 @@ mod test {
             "capital"
         );
     }
+
+    #[test]
+    fn analyze_loudness_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let resources = dir.path().join("resources");
+        std::fs::create_dir(&resources).unwrap();
+        for file in ["kuba1.mp3", "zbych1.mp3"] {
+            std::fs::write(resources.join(file), b"").unwrap();
+        }
+        let ffmpeg = dir.path().join("ffmpeg");
+        std::fs::write(
+            &ffmpeg,
+            "#!/bin/sh\nprintf 'Summary:\\n  I: -20.0 LUFS\\n' >&2\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&ffmpeg, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+        let catalogue = ResourceCatalogue::try_from_dir_path(&resources).unwrap();
+        let kuba = catalogue.random_sample("kuba").unwrap();
+        let zbych = catalogue.random_sample("zbych").unwrap();
+
+        // Without ffmpeg nothing is measured or saved.
+        let cache_path = dir.path().join("loudness.json");
+        let mut analyzer = LoudnessAnalyzer::new("/nonexistent/ffmpeg", &cache_path);
+        catalogue.analyze_loudness(&mut analyzer, -16.0);
+        assert_eq!(catalogue.loudness_gain_db(&kuba), 0.0);
+        assert!(!cache_path.exists());
+
+        // A sample removed after cataloguing doesn't stop the others.
+        std::fs::remove_file(&kuba).unwrap();
+        let mut analyzer = LoudnessAnalyzer::new(&ffmpeg.to_string_lossy(), &cache_path);
+        catalogue.analyze_loudness(&mut analyzer, -16.0);
+        assert_eq!(catalogue.loudness_gain_db(&kuba), 0.0);
+        assert_eq!(catalogue.loudness_gain_db(&zbych), 4.0);
+        assert!(cache_path.is_file());
+    }
 }
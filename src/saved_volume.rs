@@ -0,0 +1,81 @@
+use crate::atomic_write::write_atomically;
+use anyhow::{anyhow, Context};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct SavedVolumeFile {
+    volume_percent: i32,
+}
+
+/// The last volume people chose, kept in a state file so it survives restarts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SavedVolume {
+    pub path: PathBuf,
+    /// Used when nothing was saved yet.
+    pub default_percent: i32,
+    /// The restored level is kept within these bounds, so a restart fixes a muted or blasting box.
+    pub floor_percent: Option<i32>,
+    pub ceiling_percent: Option<i32>,
+}
+
+impl SavedVolume {
+    /// The level to set at startup.
+    pub fn load(&self) -> Result<i32, anyhow::Error> {
+        let saved = match std::fs::read_to_string(&self.path) {
+            Ok(text) => {
+                let file: SavedVolumeFile = serde_json::from_str(&text)
+                    .context(format!("Parse {}", self.path.display()))?;
+                Some(file.volume_percent)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!("{} not found, no saved volume", self.path.display());
+                None
+            }
+            Err(e) => return Err(anyhow!(e).context(format!("Read {}", self.path.display()))),
+        };
+        Ok(saved
+            .unwrap_or(self.default_percent)
+            .max(self.floor_percent.unwrap_or(0))
+            .min(self.ceiling_percent.unwrap_or(100)))
+    }
+
+    pub fn save(&self, volume_percent: i32) -> Result<(), anyhow::Error> {
+        write_atomically(
+            &self.path,
+            serde_json::to_string(&SavedVolumeFile { volume_percent })?.as_bytes(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn save_and_load_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let saved_volume = SavedVolume {
+            path: dir.path().join("state/volume.json"),
+            default_percent: 60,
+            floor_percent: Some(20),
+            ceiling_percent: Some(80),
+        };
+        assert_eq!(saved_volume.load().unwrap(), 60);
+        saved_volume.save(45).unwrap();
+        assert_eq!(saved_volume.load().unwrap(), 45);
+        saved_volume.save(0).unwrap();
+        assert_eq!(saved_volume.load().unwrap(), 20);
+        saved_volume.save(100).unwrap();
+        assert_eq!(saved_volume.load().unwrap(), 80);
+
+        let unsaved = SavedVolume {
+            path: dir.path().join("missing.json"),
+            default_percent: 120,
+            floor_percent: None,
+            ceiling_percent: None,
+        };
+        assert_eq!(unsaved.load().unwrap(), 100);
+    }
+}
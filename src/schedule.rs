@@ -1,4 +1,4 @@
-use crate::player::Player;
+use crate::player::{ContentSource, Player};
 use crate::resource_catalogue::ResourceCatalogue;
 use anyhow::Context;
 use chrono::{DateTime, Duration, Local, NaiveDateTime};
@@ -23,10 +23,13 @@ fn parse_and_filter_schedule(text: &str) -> Result<BTreeSet<DateTime<Local>>, an
 }
 
 pub const SCHEDULE_DEFAULT: &str = include_str!("schedule_default.yaml");
+const SAMPLE_NOISE: &str = "noise";
+const SAMPLE_LETSGO: &str = "idziemy_na_jednego";
+
 impl SchedulerImpl {
     fn new(player: Arc<Player>) -> Result<Self, anyhow::Error> {
         Ok(SchedulerImpl {
-            player: player,
+            player,
             schedule: parse_and_filter_schedule(SCHEDULE_DEFAULT)?,
         })
     }
@@ -51,7 +54,7 @@ impl Scheduler {
     ) -> Result<Self, anyhow::Error> {
         Ok(Scheduler {
             schedule_impl: Mutex::new(SchedulerImpl::new(player)?),
-            resources: resources,
+            resources,
         })
     }
 
@@ -87,6 +90,10 @@ impl Scheduler {
         Ok(())
     }
 
+    pub fn get_next_event(&self) -> Option<DateTime<Local>> {
+        self.schedule_impl.lock().unwrap().schedule.first().cloned()
+    }
+
     pub fn get_default_schedule_end_string() -> String {
         let mut schedule_end = Local::now().date_naive().and_hms_opt(23, 0, 0).unwrap();
         if schedule_end < Local::now().naive_local() {
@@ -95,10 +102,38 @@ impl Scheduler {
         schedule_end.to_string()
     }
 
-    pub async fn run_schedule(&self) -> () {
-        const SAMPLE_NOISE: &str = "noise";
-        const SAMPLE_LETSGO: &str = "idziemy_na_jednego";
+    /// Plays the announcement for the closest event once it's due, unless it was missed by more
+    /// than a minute. Either way the event is taken off the schedule.
+    fn play_due_event(&self, now: DateTime<Local>) {
+        let mut schedule_impl = self.schedule_impl.lock().unwrap();
+        if let Some(closest_event) = schedule_impl.schedule.first() {
+            if *closest_event <= now {
+                info!(
+                    "Now: {:?}, closest_event: {:?} is in the past.",
+                    now, closest_event
+                );
+                if (now - *closest_event).abs() <= chrono::Duration::seconds(60) {
+                    info!(
+                        "Now: {:?}, closest_event: {:?} happened less than 60s ago, triggering.",
+                        now, closest_event
+                    );
+                    let playlist = [SAMPLE_NOISE, SAMPLE_LETSGO]
+                        .iter()
+                        .flat_map(|sample| self.resources.random_sample(sample))
+                        .collect();
+                    schedule_impl
+                        .player
+                        .interrupt_with_local_playlist(playlist, ContentSource::Schedule)
+                        .context("play from schedule")
+                        .unwrap_or_else(|e| log::error!("Failed to play schedule: {e}"));
+                }
+                schedule_impl.schedule.pop_first().unwrap();
+                info!("Next closest_event: {:?}", schedule_impl.schedule.first());
+            }
+        }
+    }
 
+    pub async fn run_schedule(&self) -> () {
         if self.resources.random_sample(SAMPLE_NOISE).is_none()
             || self.resources.random_sample(SAMPLE_LETSGO).is_none()
         {
@@ -110,39 +145,70 @@ impl Scheduler {
         let mut last_cyclic_log = Local::now() - chrono::Duration::hours(1);
         loop {
             let now = Local::now();
-            {
-                let mut schedule_impl = self.schedule_impl.lock().unwrap();
-                if let Some(closest_event) = schedule_impl.schedule.first() {
-                    if *closest_event <= now {
-                        info!(
-                            "Now: {:?}, closest_event: {:?} is in the past.",
-                            now, closest_event
-                        );
-                        if (now - *closest_event).abs() <= chrono::Duration::seconds(60) {
-                            info!(
-                                "Now: {:?}, closest_event: {:?} happened less than 60s ago, triggering.",
-                                now, closest_event
-                            );
-                            let playlist = [SAMPLE_NOISE, SAMPLE_LETSGO]
-                                .iter()
-                                .flat_map(|sample| self.resources.random_sample(sample))
-                                .collect();
-                            schedule_impl
-                                .player
-                                .play_local_playlist(playlist)
-                                .context("play from schedule")
-                                .unwrap_or_else(|e| log::error!("Failed to play schedule: {e}"));
-                        }
-                        schedule_impl.schedule.pop_first().unwrap();
-                        info!("Next closest_event: {:?}", schedule_impl.schedule.first());
-                    }
-                    if now - last_cyclic_log > chrono::Duration::seconds(60) {
-                        info!("Next closest_event: {:?}", schedule_impl.schedule.first());
-                        last_cyclic_log = now;
-                    }
+            self.play_due_event(now);
+            if now - last_cyclic_log > chrono::Duration::seconds(60) {
+                if let Some(closest_event) = self.get_next_event() {
+                    info!("Next closest_event: {:?}", closest_event);
+                    last_cyclic_log = now;
                 }
             }
             interval.tick().await;
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::playback_backend::PlaybackSource;
+    use crate::player::{InterruptionMode, ReconnectPolicy, SourceGains};
+    use crate::recording_backend::RecordingBackend;
+
+    #[test]
+    fn play_due_event_test() {
+        let dir = tempfile::tempdir().unwrap();
+        for file in ["noise1.mp3", "idziemy_na_jednego1.mp3"] {
+            std::fs::write(dir.path().join(file), b"").unwrap();
+        }
+        let resources = Arc::new(ResourceCatalogue::try_from_dir_path(dir.path()).unwrap());
+        let backend = RecordingBackend::default();
+        let player = Arc::new(Player::new(
+            Box::new(backend.clone()),
+            InterruptionMode::Pause,
+            ReconnectPolicy {
+                max_attempts: 0,
+                base_delay: Duration::seconds(1),
+                stable_after: Duration::seconds(60),
+            },
+            SourceGains {
+                schedule: -3.0,
+                ..SourceGains::default()
+            },
+            resources.clone(),
+        ));
+        let scheduler = Scheduler::new(player, resources).unwrap();
+        let now = Local::now();
+        let events = [now - Duration::minutes(5), now, now + Duration::minutes(5)];
+        scheduler.schedule_impl.lock().unwrap().schedule = events.into_iter().collect();
+
+        // Missed by more than a minute, dropped without playing.
+        scheduler.play_due_event(now);
+        assert!(backend.recorded().is_empty());
+        assert_eq!(scheduler.get_next_event(), Some(events[1]));
+
+        scheduler.play_due_event(now);
+        let recorded = backend.recorded();
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].gain_db, -3.0);
+        let PlaybackSource::Files(files) = &recorded[0].source else {
+            panic!("Not a playlist: {:?}", recorded[0].source);
+        };
+        assert_eq!(files.len(), 2);
+        assert!(files[0].path.ends_with("noise1.mp3"));
+        assert!(files[1].path.ends_with("idziemy_na_jednego1.mp3"));
+        assert_eq!(scheduler.get_next_event(), Some(events[2]));
+
+        scheduler.play_due_event(now);
+        assert_eq!(backend.recorded().len(), 1);
+    }
+}
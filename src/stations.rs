@@ -0,0 +1,202 @@
+use crate::atomic_write::write_atomically;
+use anyhow::{anyhow, bail, Context};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub const STATIONS_DEFAULT: &str = include_str!("stations_default.yaml");
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Station {
+    pub name: String,
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logo: Option<String>,
+    /// Volume set when the station is started from the root page.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_volume_percent: Option<i32>,
+}
+
+impl Station {
+    /// Html forms send empty strings for fields left blank.
+    fn normalized(mut self) -> Self {
+        self.name = self.name.trim().to_string();
+        self.url = self.url.trim().to_string();
+        self.logo = self.logo.filter(|logo| !logo.trim().is_empty());
+        self
+    }
+
+    fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.name.is_empty() {
+            bail!("Station name is empty");
+        }
+        if self.url.is_empty() {
+            bail!("Station {} has no url", self.name);
+        }
+        if let Some(volume) = self.default_volume_percent {
+            if !(0..=100).contains(&volume) {
+                bail!("Default volume of {} out of 0..=100: {volume}", self.name);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_stations(text: &str) -> Result<Vec<Station>, anyhow::Error> {
+    let stations: Vec<Station> = serde_yaml::from_str(text).context("Parse stations")?;
+    let stations: Vec<Station> = stations.into_iter().map(Station::normalized).collect();
+    for (index, station) in stations.iter().enumerate() {
+        station.validate()?;
+        if stations[..index].iter().any(|s| s.name == station.name) {
+            bail!("Station {} defined twice", station.name);
+        }
+    }
+    Ok(stations)
+}
+
+/// Radio stations offered on the root page. Loaded from a yaml file, which gets rewritten on
+/// every change. Without the file the built-in stations are used until the first change.
+pub struct StationRegistry {
+    stations: Mutex<Vec<Station>>,
+    path: PathBuf,
+}
+
+impl StationRegistry {
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let stations = match std::fs::read_to_string(path) {
+            Ok(text) => parse_stations(&text).context(format!("Load {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!("{} not found, using default stations", path.display());
+                parse_stations(STATIONS_DEFAULT)?
+            }
+            Err(e) => return Err(anyhow!(e).context(format!("Read {}", path.display()))),
+        };
+        info!(
+            "Stations: {:?}",
+            stations.iter().map(|s| &s.name).collect::<Vec<_>>()
+        );
+        Ok(StationRegistry {
+            stations: Mutex::new(stations),
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn list(&self) -> Vec<Station> {
+        self.stations.lock().unwrap().clone()
+    }
+
+    pub fn get(&self, name: &str) -> Option<Station> {
+        self.stations
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|s| s.name == name.trim())
+            .cloned()
+    }
+
+    pub fn add(&self, station: Station) -> Result<(), anyhow::Error> {
+        let station = station.normalized();
+        station.validate()?;
+        self.modify(|stations| {
+            if stations.iter().any(|s| s.name == station.name) {
+                bail!("Station {} already exists", station.name);
+            }
+            info!("Add station {station:?}");
+            stations.push(station);
+            Ok(())
+        })
+    }
+
+    /// Replaces the station with the same name.
+    pub fn update(&self, station: Station) -> Result<(), anyhow::Error> {
+        let station = station.normalized();
+        station.validate()?;
+        self.modify(|stations| {
+            let existing = stations
+                .iter_mut()
+                .find(|s| s.name == station.name)
+                .ok_or(anyhow!("No station named {}", station.name))?;
+            info!("Update station {station:?}");
+            *existing = station;
+            Ok(())
+        })
+    }
+
+    pub fn remove(&self, name: &str) -> Result<(), anyhow::Error> {
+        self.modify(|stations| {
+            let index = stations
+                .iter()
+                .position(|s| s.name == name.trim())
+                .ok_or(anyhow!("No station named {name}"))?;
+            info!("Remove station {}", stations[index].name);
+            stations.remove(index);
+            Ok(())
+        })
+    }
+
+    /// Applies the change only if it can be saved.
+    fn modify(
+        &self,
+        change: impl FnOnce(&mut Vec<Station>) -> Result<(), anyhow::Error>,
+    ) -> Result<(), anyhow::Error> {
+        let mut stations = self.stations.lock().unwrap();
+        let mut changed = stations.clone();
+        change(&mut changed)?;
+        self.save(&changed)
+            .context(format!("Save stations to {}", self.path.display()))?;
+        *stations = changed;
+        Ok(())
+    }
+
+    fn save(&self, stations: &[Station]) -> Result<(), anyhow::Error> {
+        write_atomically(&self.path, serde_yaml::to_string(stations)?.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn default_stations_test() {
+        let stations = parse_stations(STATIONS_DEFAULT).unwrap();
+        assert_eq!(stations[0].name, "ChilliZet");
+        assert!(parse_stations("- {name: A, url: a}\n- {name: A, url: b}").is_err());
+        assert!(parse_stations("- {name: A, url: a, default_volume_percent: 101}").is_err());
+    }
+
+    #[test]
+    fn crud_persists_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stations/stations.yaml");
+        let registry = StationRegistry::load(&path).unwrap();
+        let default_count = registry.list().len();
+
+        let station = Station {
+            name: " Jazz ".to_string(),
+            url: "http://jazz/stream".to_string(),
+            logo: Some("".to_string()),
+            default_volume_percent: Some(40),
+        };
+        registry.add(station.clone()).unwrap();
+        assert!(registry.add(station).is_err());
+        registry
+            .update(Station {
+                name: "Jazz".to_string(),
+                url: "http://jazz/other".to_string(),
+                logo: None,
+                default_volume_percent: None,
+            })
+            .unwrap();
+        registry.remove("ChilliZet").unwrap();
+        assert!(registry.remove("ChilliZet").is_err());
+
+        let reloaded = StationRegistry::load(&path).unwrap();
+        assert_eq!(reloaded.list(), registry.list());
+        assert_eq!(reloaded.list().len(), default_count);
+        let jazz = reloaded.get("Jazz").unwrap();
+        assert_eq!(jazz.url, "http://jazz/other");
+        assert_eq!(jazz.logo, None);
+    }
+}
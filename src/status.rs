@@ -0,0 +1,42 @@
+use crate::icy_metadata::{IcyMetadata, TitleChange};
+use crate::player::{Player, PlayerStatus};
+use crate::schedule::Scheduler;
+use crate::volume_caps::VolumeCap;
+use crate::volume_controller::VolumeController;
+use chrono::{DateTime, Local};
+use log::*;
+use serde::Serialize;
+
+#[derive(Serialize, Debug)]
+pub struct Status {
+    pub player: PlayerStatus,
+    pub volume_percent: Option<i32>,
+    pub muted: bool,
+    pub volume_cap: Option<VolumeCap>,
+    pub next_scheduled_event: Option<DateTime<Local>>,
+    pub now_playing: Option<String>,
+    pub title_history: Vec<TitleChange>,
+}
+
+impl Status {
+    pub fn collect(
+        player: &Player,
+        volume_controller: &VolumeController,
+        scheduler: &Scheduler,
+        icy_metadata: &IcyMetadata,
+    ) -> Self {
+        let volume = volume_controller
+            .get_status()
+            .inspect_err(|e| warn!("Status without volume: {e:#}"))
+            .ok();
+        Status {
+            player: player.status(),
+            volume_percent: volume.as_ref().map(|v| v.volume_percent),
+            muted: volume.as_ref().is_some_and(|v| v.muted),
+            volume_cap: volume.and_then(|v| v.cap),
+            next_scheduled_event: scheduler.get_next_event(),
+            now_playing: icy_metadata.current_title(),
+            title_history: icy_metadata.history(),
+        }
+    }
+}
@@ -0,0 +1,295 @@
+use anyhow::{anyhow, bail, Context};
+use log::*;
+use regex::Regex;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, Url};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+const MAX_PLAYLIST_SIZE: usize = 64 * 1024;
+const MAX_PLAYLIST_DEPTH: usize = 3;
+
+#[derive(Debug, PartialEq)]
+enum PlaylistFormat {
+    Pls,
+    M3u,
+    Xspf,
+}
+
+/// Turns station links into urls a player can open directly: follows redirects and unpacks
+/// PLS, M3U and XSPF playlists, picking the first entry that answers. HLS playlists are left
+/// to the player.
+pub struct StreamResolver {
+    client: Client,
+    cache: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl StreamResolver {
+    pub fn new() -> Result<Self, anyhow::Error> {
+        Ok(StreamResolver {
+            client: Client::builder()
+                .connect_timeout(Duration::from_secs(5))
+                .read_timeout(Duration::from_secs(5))
+                .user_agent(concat!("fosiaudio_chilli/", env!("CARGO_PKG_VERSION")))
+                .build()
+                .context("Create http client")?,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub async fn resolve(&self, url: &str) -> Result<String, anyhow::Error> {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Ok(url.to_string());
+        }
+        if let Some((resolved, at)) = self.cache.lock().unwrap().get(url) {
+            if at.elapsed() < CACHE_TTL {
+                info!("Resolved {url} to {resolved} (cached)");
+                return Ok(resolved.clone());
+            }
+        }
+        let resolved = self
+            .resolve_uncached(url.to_string(), 0)
+            .await
+            .context(format!("Resolve stream {url}"))?;
+        info!("Resolved {url} to {resolved}");
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(url.to_string(), (resolved.clone(), Instant::now()));
+        Ok(resolved)
+    }
+
+    /// Like `resolve`, but ignores what was cached for the link, because the stream it led to
+    /// stopped working.
+    pub async fn resolve_again(&self, url: &str) -> Result<String, anyhow::Error> {
+        self.cache.lock().unwrap().remove(url);
+        self.resolve(url).await
+    }
+
+    async fn resolve_uncached(&self, url: String, depth: usize) -> Result<String, anyhow::Error> {
+        let response = self
+            .client
+            .get(&url)
+            .send()
+            .await
+            .context(format!("GET {url}"))?;
+        if !response.status().is_success() {
+            bail!("GET {url}: HTTP {}", response.status());
+        }
+        let final_url = response.url().clone();
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let Some(format) = detect_playlist_format(&content_type, final_url.path()) else {
+            return Ok(final_url.to_string());
+        };
+        if depth >= MAX_PLAYLIST_DEPTH {
+            bail!("Playlists nested too deep at {final_url}");
+        }
+        let body = read_limited(response).await?;
+        if format == PlaylistFormat::M3u && body.contains("#EXT-X-") {
+            // HLS, the player knows how to follow it.
+            return Ok(final_url.to_string());
+        }
+        let entries = parse_playlist(&format, &body, &final_url);
+        if entries.is_empty() {
+            bail!("{format:?} playlist {final_url} has no entries");
+        }
+
+        let mut failures = Vec::new();
+        for entry in entries {
+            match Box::pin(self.resolve_uncached(entry.clone(), depth + 1)).await {
+                Ok(resolved) => return Ok(resolved),
+                Err(e) => {
+                    warn!("Playlist entry {entry} doesn't work: {e:#}");
+                    failures.push(format!("{e:#}"));
+                }
+            }
+        }
+        Err(anyhow!(
+            "None of the entries of {final_url} work: {}",
+            failures.join("; ")
+        ))
+    }
+}
+
+async fn read_limited(mut response: reqwest::Response) -> Result<String, anyhow::Error> {
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.context("Read playlist")? {
+        body.extend_from_slice(&chunk);
+        if body.len() > MAX_PLAYLIST_SIZE {
+            bail!("Playlist larger than {MAX_PLAYLIST_SIZE} bytes");
+        }
+    }
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+fn detect_playlist_format(content_type: &str, path: &str) -> Option<PlaylistFormat> {
+    let content_type = content_type.to_lowercase();
+    let path = path.to_lowercase();
+    if content_type.contains("scpls") || path.ends_with(".pls") {
+        Some(PlaylistFormat::Pls)
+    } else if content_type.contains("mpegurl") || path.ends_with(".m3u") || path.ends_with(".m3u8")
+    {
+        Some(PlaylistFormat::M3u)
+    } else if content_type.contains("xspf") || path.ends_with(".xspf") {
+        Some(PlaylistFormat::Xspf)
+    } else {
+        None
+    }
+}
+
+fn parse_playlist(format: &PlaylistFormat, body: &str, base: &Url) -> Vec<String> {
+    let entries = match format {
+        PlaylistFormat::Pls => parse_pls(body),
+        PlaylistFormat::M3u => parse_m3u(body),
+        PlaylistFormat::Xspf => parse_xspf(body),
+    };
+    entries
+        .iter()
+        .filter_map(|entry| base.join(entry).ok())
+        .map(|url| url.to_string())
+        .collect()
+}
+
+/// Entries in the order of their `FileN=` numbers.
+fn parse_pls(body: &str) -> Vec<String> {
+    let mut entries: Vec<(u32, String)> = body
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.trim().split_once('=')?;
+            let number = key
+                .trim()
+                .to_lowercase()
+                .strip_prefix("file")?
+                .parse()
+                .ok()?;
+            Some((number, value.trim().to_string()))
+        })
+        .collect();
+    entries.sort_by_key(|(number, _)| *number);
+    entries.into_iter().map(|(_, url)| url).collect()
+}
+
+fn parse_m3u(body: &str) -> Vec<String> {
+    body.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+fn parse_xspf(body: &str) -> Vec<String> {
+    let re = Regex::new(r"(?s)<location>\s*(?<url>.*?)\s*</location>").unwrap();
+    re.captures_iter(body)
+        .map(|caps| caps["url"].replace("&amp;", "&"))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use http::{Request, Response, StatusCode};
+    use http_body_util::Full;
+    use hyper::body::Bytes;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper_util::rt::TokioIo;
+    use std::convert::Infallible;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn parse_playlists_test() {
+        let base = Url::parse("http://example.com/radio/station.pls").unwrap();
+        let pls =
+            "[playlist]\nNumberOfEntries=2\nFile2=http://b/stream\nFile1=/a/stream\nTitle1=A\n";
+        assert_eq!(
+            parse_playlist(&PlaylistFormat::Pls, pls, &base),
+            ["http://example.com/a/stream", "http://b/stream"]
+        );
+        let m3u = "#EXTM3U\n#EXTINF:-1,Radio\nstream.mp3\n\nhttp://c/stream\n";
+        assert_eq!(
+            parse_playlist(&PlaylistFormat::M3u, m3u, &base),
+            ["http://example.com/radio/stream.mp3", "http://c/stream"]
+        );
+        let xspf = r#"<playlist><trackList><track>
+            <location>http://d/stream?a=1&amp;b=2</location></track></trackList></playlist>"#;
+        assert_eq!(
+            parse_playlist(&PlaylistFormat::Xspf, xspf, &base),
+            ["http://d/stream?a=1&b=2"]
+        );
+    }
+
+    #[test]
+    fn detect_playlist_format_test() {
+        assert_eq!(
+            detect_playlist_format("audio/x-scpls", "/stream"),
+            Some(PlaylistFormat::Pls)
+        );
+        assert_eq!(
+            detect_playlist_format("", "/pr3/playlist.M3U8"),
+            Some(PlaylistFormat::M3u)
+        );
+        assert_eq!(detect_playlist_format("audio/mpeg", "/stream"), None);
+    }
+
+    async fn serve(
+        request: Request<hyper::body::Incoming>,
+    ) -> Result<Response<Full<Bytes>>, Infallible> {
+        let response = match request.uri().path() {
+            "/redirect" => Response::builder()
+                .status(StatusCode::FOUND)
+                .header("Location", "/station.pls")
+                .body(Full::default()),
+            "/station.pls" => Response::builder()
+                .header("Content-Type", "audio/x-scpls")
+                .body(Full::from("[playlist]\nFile1=/dead\nFile2=/stream\n")),
+            "/broken.m3u" => Response::builder().body(Full::from("/dead\n/missing\n")),
+            "/stream" => Response::builder()
+                .header("Content-Type", "audio/mpeg")
+                .body(Full::from(vec![0u8; 1024])),
+            _ => Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Full::default()),
+        };
+        Ok(response.unwrap())
+    }
+
+    async fn start_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(
+                    http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(serve)),
+                );
+            }
+        });
+        format!("http://{addr}")
+    }
+
+    #[tokio::test]
+    async fn resolve_test() {
+        let base = start_server().await;
+        let resolver = StreamResolver::new().unwrap();
+        assert_eq!(
+            resolver.resolve(&format!("{base}/redirect")).await.unwrap(),
+            format!("{base}/stream")
+        );
+        assert_eq!(
+            resolver.resolve("/opt/benny.mp3").await.unwrap(),
+            "/opt/benny.mp3"
+        );
+        let error = resolver
+            .resolve(&format!("{base}/broken.m3u"))
+            .await
+            .unwrap_err();
+        assert!(format!("{error:#}").contains("None of the entries"));
+    }
+}
@@ -0,0 +1,277 @@
+use anyhow::{anyhow, Context};
+use log::*;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::SystemTime;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum TtsEngine {
+    /// Nicks without samples are announced as "unknown".
+    None,
+    EspeakNg,
+    Piper,
+}
+
+/// Speaks nicks that have no recorded sample. Synthesized speech is converted to mp3 like the
+/// recorded samples, so playlists can mix both, and kept in the cache directory. The oldest
+/// files are removed once there are more than `max_cached_files`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tts {
+    pub engine: TtsEngine,
+    /// Defaults to `espeak-ng` or `piper`.
+    pub program_path: Option<String>,
+    /// espeak-ng voice variant like `m3` or `f2`, path to the `.onnx` model for piper.
+    pub voice: Option<String>,
+    /// espeak-ng language, piper models come with their own.
+    pub language: String,
+    pub cache_dir: PathBuf,
+    pub max_cached_files: usize,
+    pub ffmpeg_path: String,
+}
+
+impl Tts {
+    /// Path of an mp3 speaking the text, synthesized unless cached.
+    pub fn synthesize(&self, text: &str) -> Result<PathBuf, anyhow::Error> {
+        if let Some(path) = self.cached(text) {
+            return Ok(path);
+        }
+        let path = self.cache_path(text);
+        info!("Synthesizing {text:?} with {:?}", self.engine);
+        std::fs::create_dir_all(&self.cache_dir)
+            .context(format!("Create {}", self.cache_dir.display()))?;
+        let wav = tempfile::Builder::new()
+            .suffix(".wav")
+            .tempfile_in(&self.cache_dir)?;
+        self.synthesize_wav(text, wav.path())?;
+        let mp3 = tempfile::Builder::new()
+            .suffix(".mp3")
+            .tempfile_in(&self.cache_dir)?;
+        run(Command::new(&self.ffmpeg_path)
+            .args(["-hide_banner", "-loglevel", "error", "-y", "-i"])
+            .arg(wav.path())
+            .args(["-ar", "44100", "-ac", "2", "-b:a", "128k"])
+            .arg(mp3.path()))?;
+        mp3.persist(&path)?;
+        if let Err(e) = self.prune_cache() {
+            warn!("Failed to prune {}: {e:#}", self.cache_dir.display());
+        }
+        Ok(path)
+    }
+
+    /// Removes the oldest synthesized files beyond `max_cached_files`.
+    fn prune_cache(&self) -> Result<(), anyhow::Error> {
+        let mut files: Vec<(SystemTime, PathBuf)> = Vec::new();
+        for entry in std::fs::read_dir(&self.cache_dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            // Temporary files of a synthesis in progress start with a dot.
+            if name.starts_with('.') || !name.ends_with(".mp3") {
+                continue;
+            }
+            files.push((entry.metadata()?.modified()?, entry.path()));
+        }
+        if files.len() <= self.max_cached_files {
+            return Ok(());
+        }
+        files.sort();
+        for (_, path) in &files[..files.len() - self.max_cached_files] {
+            info!("Remove cached speech {}", path.display());
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Path of the mp3 speaking the text if it was synthesized before.
+    pub fn cached(&self, text: &str) -> Option<PathBuf> {
+        Some(self.cache_path(text)).filter(|path| path.is_file())
+    }
+
+    fn synthesize_wav(&self, text: &str, wav_path: &Path) -> Result<(), anyhow::Error> {
+        let mut command = self.command(wav_path);
+        match self.engine {
+            TtsEngine::Piper => {
+                let mut child = command
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::piped())
+                    .spawn()
+                    .map_err(|e| anyhow!(e).context(format!("Run {}", self.program())))?;
+                child
+                    .stdin
+                    .take()
+                    .ok_or(anyhow!("No stdin of {}", self.program()))?
+                    .write_all(text.as_bytes())?;
+                let output = child.wait_with_output()?;
+                check_status(self.program(), &output)
+            }
+            // Would be taken for an option otherwise.
+            _ => run(command.arg(text.trim_start_matches('-'))),
+        }
+    }
+
+    fn program(&self) -> &str {
+        match (&self.program_path, self.engine) {
+            (Some(path), _) => path,
+            (None, TtsEngine::Piper) => "piper",
+            (None, _) => "espeak-ng",
+        }
+    }
+
+    /// espeak-ng takes the text as the last argument, piper on stdin.
+    fn command(&self, wav_path: &Path) -> Command {
+        let mut command = Command::new(self.program());
+        match self.engine {
+            TtsEngine::Piper => {
+                if let Some(model) = &self.voice {
+                    command.arg("--model").arg(model);
+                }
+                command.arg("--output_file").arg(wav_path);
+            }
+            _ => {
+                let voice = match &self.voice {
+                    Some(variant) => format!("{}+{variant}", self.language),
+                    None => self.language.clone(),
+                };
+                command.arg("-v").arg(voice).arg("-w").arg(wav_path);
+            }
+        }
+        command
+    }
+
+    /// Readable name, and a hash of everything that changes how it sounds. The hash must not
+    /// change between builds, or the cache would be thrown away with every upgrade.
+    fn cache_path(&self, text: &str) -> PathBuf {
+        let engine = match self.engine {
+            TtsEngine::None => "none",
+            TtsEngine::EspeakNg => "espeak-ng",
+            TtsEngine::Piper => "piper",
+        };
+        let key = [
+            text,
+            engine,
+            self.voice.as_deref().unwrap_or(""),
+            &self.language,
+        ]
+        .join("\0");
+        let name: String = text
+            .chars()
+            .filter(|c| c.is_alphanumeric())
+            .take(32)
+            .collect();
+        self.cache_dir
+            .join(format!("{name}-{:016x}.mp3", fnv1a(key.as_bytes())))
+    }
+}
+
+/// 64-bit FNV-1a, stable unlike the hashers of the standard library.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn check_status(program: &str, output: &std::process::Output) -> Result<(), anyhow::Error> {
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{program} failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+fn run(command: &mut Command) -> Result<(), anyhow::Error> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let output = command
+        .output()
+        .map_err(|e| anyhow!(e).context(format!("Run {program}")))?;
+    check_status(&program, &output)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(command: &Command) -> Vec<String> {
+        command
+            .get_args()
+            .map(|a| a.to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn command_and_cache_test() {
+        let espeak = Tts {
+            engine: TtsEngine::EspeakNg,
+            program_path: None,
+            voice: Some("m3".to_string()),
+            language: "pl".to_string(),
+            cache_dir: PathBuf::from("/cache"),
+            max_cached_files: 10,
+            ffmpeg_path: "ffmpeg".to_string(),
+        };
+        let command = espeak.command(Path::new("/tmp/a.wav"));
+        assert_eq!(command.get_program(), "espeak-ng");
+        assert_eq!(args(&command), ["-v", "pl+m3", "-w", "/tmp/a.wav"]);
+
+        let piper = Tts {
+            engine: TtsEngine::Piper,
+            voice: Some("/voices/pl.onnx".to_string()),
+            ..espeak.clone()
+        };
+        assert_eq!(
+            args(&piper.command(Path::new("/tmp/a.wav"))),
+            ["--model", "/voices/pl.onnx", "--output_file", "/tmp/a.wav"]
+        );
+
+        let path = espeak.cache_path("Żółw ../");
+        assert_eq!(path.parent(), Some(Path::new("/cache")));
+        assert!(path
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with("Żółw-"));
+        assert_eq!(path, espeak.cache_path("Żółw ../"));
+        assert_ne!(path, piper.cache_path("Żółw ../"));
+        // Pinned, cached speech would be lost if it changed.
+        assert_eq!(
+            espeak.cache_path("kuba"),
+            Path::new("/cache/kuba-85a5c6576f493105.mp3")
+        );
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+    }
+
+    #[test]
+    fn prune_cache_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let tts = Tts {
+            engine: TtsEngine::EspeakNg,
+            program_path: Some("true".to_string()),
+            voice: None,
+            language: "pl".to_string(),
+            cache_dir: dir.path().to_path_buf(),
+            max_cached_files: 2,
+            // The converted mp3 is the empty temporary file.
+            ffmpeg_path: "true".to_string(),
+        };
+        let first = tts.synthesize("kuba").unwrap();
+        let old = SystemTime::now() - std::time::Duration::from_secs(60);
+        std::fs::File::options()
+            .write(true)
+            .open(&first)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        std::fs::write(dir.path().join(".tmp123.wav"), b"").unwrap();
+        tts.synthesize("zbych").unwrap();
+        assert!(first.is_file());
+        tts.synthesize("ania").unwrap();
+        assert!(!first.is_file());
+        assert!(tts.cached("zbych").is_some());
+        assert!(tts.cached("ania").is_some());
+        assert!(dir.path().join(".tmp123.wav").is_file());
+    }
+}
@@ -0,0 +1,397 @@
+use anyhow::{anyhow, Context};
+use log::*;
+use regex::Regex;
+use std::process::Command;
+
+/// Reads and sets the output volume of some mixer, in percent.
+pub trait VolumeBackend: Send + Sync {
+    fn name(&self) -> &'static str;
+    /// Fails with an explanation if the backend can't drive the volume on this machine.
+    fn validate(&self) -> Result<(), anyhow::Error>;
+    fn get_volume(&self) -> Result<i32, anyhow::Error>;
+    fn set_volume(&self, percent: i32) -> Result<(), anyhow::Error>;
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum VolumeBackendKind {
+    /// First of wpctl, pactl and ALSA that works.
+    Auto,
+    Amixer,
+    /// In-process ALSA mixer when built with the `alsa` feature, amixer otherwise.
+    Alsa,
+    Pactl,
+    Wpctl,
+}
+
+/// Creates the backend and checks that it works.
+pub fn create_volume_backend(
+    kind: VolumeBackendKind,
+    mixer: MixerConfig,
+    sink: Option<String>,
+) -> Result<Box<dyn VolumeBackend>, anyhow::Error> {
+    let backend: Box<dyn VolumeBackend> = match kind {
+        VolumeBackendKind::Auto => return detect_volume_backend(mixer, sink),
+        VolumeBackendKind::Amixer => Box::new(AmixerBackend { mixer }),
+        VolumeBackendKind::Alsa => alsa_backend(mixer),
+        VolumeBackendKind::Pactl => Box::new(PactlBackend::new(sink)),
+        VolumeBackendKind::Wpctl => Box::new(WpctlBackend::new(sink)),
+    };
+    backend
+        .validate()
+        .context(format!("Validate {} volume backend", backend.name()))?;
+    Ok(backend)
+}
+
+fn detect_volume_backend(
+    mixer: MixerConfig,
+    sink: Option<String>,
+) -> Result<Box<dyn VolumeBackend>, anyhow::Error> {
+    let candidates: [Box<dyn VolumeBackend>; 2] = [
+        Box::new(WpctlBackend::new(sink.clone())),
+        Box::new(PactlBackend::new(sink)),
+    ];
+    for backend in candidates {
+        match backend.validate() {
+            Ok(()) => {
+                info!("Detected {} volume backend", backend.name());
+                return Ok(backend);
+            }
+            Err(e) => debug!("No {} volume backend: {e:#}", backend.name()),
+        }
+    }
+    // ALSA last, its error is the one worth reporting on plain ALSA boxes.
+    let backend = alsa_backend(mixer);
+    backend
+        .validate()
+        .context("No working volume backend among wpctl, pactl and ALSA")?;
+    Ok(backend)
+}
+
+/// The in-process mixer if it works, amixer otherwise.
+fn alsa_backend(mixer: MixerConfig) -> Box<dyn VolumeBackend> {
+    #[cfg(feature = "alsa")]
+    {
+        let native = crate::alsa_mixer::AlsaMixerBackend::open(mixer.clone())
+            .map_err(anyhow::Error::from)
+            .and_then(|backend| backend.validate().map(|_| backend));
+        match native {
+            Ok(backend) => return Box::new(backend),
+            Err(e) => warn!("{e:#}, falling back to amixer"),
+        }
+    }
+    #[cfg(not(feature = "alsa"))]
+    debug!("Built without the alsa feature, using amixer");
+    Box::new(AmixerBackend { mixer })
+}
+
+/// Runs the command and returns its stdout, failing with its stderr.
+fn run(command: &mut Command) -> Result<String, anyhow::Error> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let output = command
+        .output()
+        .map_err(|e| anyhow!(e).context(format!("Run {program}")))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{program} failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    String::from_utf8(output.stdout).context(format!("Read output of {program}"))
+}
+
+/// Simple ALSA mixer control driven with `amixer`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MixerConfig {
+    /// Passed as `amixer -c`, amixer's default card when `None`.
+    pub card: Option<String>,
+    pub control: String,
+    /// amixer channel names, like `frontleft` or `front`. Empty means all channels of the control.
+    pub channels: Vec<String>,
+}
+
+impl MixerConfig {
+    fn amixer(&self) -> Command {
+        let mut command = Command::new("amixer");
+        if let Some(card) = &self.card {
+            command.args(["-c", card]);
+        }
+        command
+    }
+
+    fn describe(&self) -> String {
+        match &self.card {
+            Some(card) => format!("'{}' on card {card}", self.control),
+            None => format!("'{}'", self.control),
+        }
+    }
+
+    /// Checks that the control exists and has the configured channels.
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        let output = get_control(self).map_err(|e| match list_controls(self) {
+            Ok(controls) => e.context(format!("Available controls:\n{controls}")),
+            Err(_) => e,
+        })?;
+        parse_volume(&output, &self.channels)
+            .context(format!("Unusable mixer control {}", self.describe()))?;
+        let missing: Vec<&String> = self
+            .channels
+            .iter()
+            .filter(|channel| matching_channels(&output, channel).is_empty())
+            .collect();
+        if !missing.is_empty() {
+            return Err(anyhow!(
+                "Mixer control {} has no channels {missing:?}:\n{output}",
+                self.describe()
+            ));
+        }
+        info!("Using mixer control {}", self.describe());
+        Ok(())
+    }
+}
+
+pub struct AmixerBackend {
+    mixer: MixerConfig,
+}
+
+impl VolumeBackend for AmixerBackend {
+    fn name(&self) -> &'static str {
+        "amixer"
+    }
+
+    fn validate(&self) -> Result<(), anyhow::Error> {
+        self.mixer.validate()
+    }
+
+    fn get_volume(&self) -> Result<i32, anyhow::Error> {
+        parse_volume(&get_control(&self.mixer)?, &self.mixer.channels)
+    }
+
+    fn set_volume(&self, percent: i32) -> Result<(), anyhow::Error> {
+        let vol_percent = percent.to_string() + "%";
+        let mut command = self.mixer.amixer();
+        command.args(["sset", &self.mixer.control]);
+        if self.mixer.channels.is_empty() {
+            command.arg(&vol_percent);
+        }
+        for channel in &self.mixer.channels {
+            command.args([channel, &vol_percent]);
+        }
+        run(&mut command).context("Set volume with amixer failed")?;
+        Ok(())
+    }
+}
+
+fn get_control(mixer: &MixerConfig) -> Result<String, anyhow::Error> {
+    run(mixer.amixer().args(["sget", &mixer.control])).context(format!(
+        "Get mixer control {} with amixer",
+        mixer.describe()
+    ))
+}
+
+fn list_controls(mixer: &MixerConfig) -> Result<String, anyhow::Error> {
+    let output = mixer.amixer().arg("scontrols").output()?;
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Lines of `amixer sget` output for the channel, e.g. `front` matches `Front Left` and
+/// `Front Right`.
+fn matching_channels<'a>(output: &'a str, channel: &str) -> Vec<&'a str> {
+    let channel = channel.to_lowercase();
+    output
+        .lines()
+        .filter(|line| {
+            line.split_once(':').is_some_and(|(name, _)| {
+                let name = name.trim().replace(' ', "").to_lowercase();
+                name != "playbackchannels" && name.starts_with(&channel)
+            })
+        })
+        .collect()
+}
+
+fn parse_volume(output: &str, channels: &[String]) -> Result<i32, anyhow::Error> {
+    let re = Regex::new(r"\[(?<percent>\d+)%]")?;
+    let lines = match channels.first() {
+        Some(channel) => matching_channels(output, channel),
+        None => vec![output],
+    };
+    let caps = lines
+        .iter()
+        .find_map(|line| re.captures(line))
+        .ok_or(anyhow!("Unable to parse current volume from: {:?}", output))?;
+    let percent = &caps["percent"];
+    Ok(percent.parse()?)
+}
+
+/// PulseAudio, or PipeWire through its pulse compatibility layer.
+pub struct PactlBackend {
+    sink: String,
+}
+
+impl PactlBackend {
+    pub fn new(sink: Option<String>) -> Self {
+        PactlBackend {
+            sink: sink.unwrap_or("@DEFAULT_SINK@".to_string()),
+        }
+    }
+}
+
+impl VolumeBackend for PactlBackend {
+    fn name(&self) -> &'static str {
+        "pactl"
+    }
+
+    fn validate(&self) -> Result<(), anyhow::Error> {
+        let volume = self.get_volume()?;
+        info!("Using pactl sink {} at {volume}%", self.sink);
+        Ok(())
+    }
+
+    /// Volume of the first channel.
+    fn get_volume(&self) -> Result<i32, anyhow::Error> {
+        let output = run(Command::new("pactl").args(["get-sink-volume", &self.sink]))?;
+        let re = Regex::new(r"(?<percent>\d+)%")?;
+        let caps = re
+            .captures(&output)
+            .ok_or(anyhow!("Unable to parse current volume from: {:?}", output))?;
+        Ok(caps["percent"].parse()?)
+    }
+
+    fn set_volume(&self, percent: i32) -> Result<(), anyhow::Error> {
+        run(Command::new("pactl").args(["set-sink-volume", &self.sink, &format!("{percent}%")]))
+            .context("Set volume with pactl failed")?;
+        Ok(())
+    }
+}
+
+/// PipeWire through WirePlumber.
+pub struct WpctlBackend {
+    sink: String,
+}
+
+impl WpctlBackend {
+    pub fn new(sink: Option<String>) -> Self {
+        WpctlBackend {
+            sink: sink.unwrap_or("@DEFAULT_AUDIO_SINK@".to_string()),
+        }
+    }
+}
+
+impl VolumeBackend for WpctlBackend {
+    fn name(&self) -> &'static str {
+        "wpctl"
+    }
+
+    fn validate(&self) -> Result<(), anyhow::Error> {
+        let volume = self.get_volume()?;
+        info!("Using wpctl sink {} at {volume}%", self.sink);
+        Ok(())
+    }
+
+    fn get_volume(&self) -> Result<i32, anyhow::Error> {
+        parse_wpctl_volume(&run(Command::new("wpctl").args(["get-volume", &self.sink]))?)
+    }
+
+    fn set_volume(&self, percent: i32) -> Result<(), anyhow::Error> {
+        run(Command::new("wpctl").args(["set-volume", &self.sink, &format!("{percent}%")]))
+            .context("Set volume with wpctl failed")?;
+        Ok(())
+    }
+}
+
+/// `Volume: 0.40`, optionally followed by `[MUTED]`.
+fn parse_wpctl_volume(output: &str) -> Result<i32, anyhow::Error> {
+    let re = Regex::new(r"Volume: (?<volume>\d+(\.\d+)?)")?;
+    let caps = re
+        .captures(output)
+        .ok_or(anyhow!("Unable to parse current volume from: {:?}", output))?;
+    let volume: f64 = caps["volume"].parse()?;
+    Ok((volume * 100.0).round() as i32)
+}
+
+/// Remembers the volume and nothing else, for tests.
+#[cfg(test)]
+pub struct FakeVolumeBackend {
+    volume: std::sync::Mutex<i32>,
+}
+
+#[cfg(test)]
+impl FakeVolumeBackend {
+    pub fn new(volume: i32) -> Self {
+        FakeVolumeBackend {
+            volume: std::sync::Mutex::new(volume),
+        }
+    }
+}
+
+#[cfg(test)]
+impl VolumeBackend for FakeVolumeBackend {
+    fn name(&self) -> &'static str {
+        "fake"
+    }
+
+    fn validate(&self) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+
+    fn get_volume(&self) -> Result<i32, anyhow::Error> {
+        Ok(*self.volume.lock().unwrap())
+    }
+
+    fn set_volume(&self, percent: i32) -> Result<(), anyhow::Error> {
+        info!("Fake volume set to {percent}%");
+        *self.volume.lock().unwrap() = percent;
+        Ok(())
+    }
+}
+
+/// What every backend has to do. Puts the volume back afterwards, as it may drive a real mixer.
+#[cfg(test)]
+pub fn check_volume_backend(backend: &dyn VolumeBackend) {
+    backend.validate().unwrap();
+    let volume = backend.get_volume().unwrap();
+    for percent in [0, 37, 100] {
+        backend.set_volume(percent).unwrap();
+        assert_eq!(backend.get_volume().unwrap(), percent, "{}", backend.name());
+    }
+    backend.set_volume(volume).unwrap();
+    assert_eq!(backend.get_volume().unwrap(), volume);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SGET_OUTPUT: &str = "Simple mixer control 'PCM',0
+  Capabilities: pvolume pswitch
+  Playback channels: Front Left - Front Right
+  Limits: Playback 0 - 255
+  Mono:
+  Front Left: Playback 150 [59%] [on]
+  Front Right: Playback 200 [78%] [on]
+";
+
+    #[test]
+    fn parse_volume_test() {
+        assert_eq!(parse_volume(SGET_OUTPUT, &[]).unwrap(), 59);
+        assert_eq!(
+            parse_volume(SGET_OUTPUT, &["frontright".to_string()]).unwrap(),
+            78
+        );
+        assert_eq!(matching_channels(SGET_OUTPUT, "front").len(), 2);
+        assert!(matching_channels(SGET_OUTPUT, "rear").is_empty());
+        assert!(parse_volume(SGET_OUTPUT, &["rear".to_string()]).is_err());
+    }
+
+    #[test]
+    fn fake_backend_test() {
+        check_volume_backend(&FakeVolumeBackend::new(50));
+    }
+
+    #[test]
+    fn parse_wpctl_volume_test() {
+        assert_eq!(parse_wpctl_volume("Volume: 0.40\n").unwrap(), 40);
+        assert_eq!(parse_wpctl_volume("Volume: 1.00 [MUTED]\n").unwrap(), 100);
+        assert!(parse_wpctl_volume("Error").is_err());
+    }
+}
@@ -0,0 +1,109 @@
+use anyhow::{anyhow, Context};
+use chrono::NaiveTime;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Maximum volume between two times of day. The window wraps around midnight when `to` is
+/// earlier than `from`, and lasts all day when they are equal.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct VolumeCap {
+    pub from: NaiveTime,
+    pub to: NaiveTime,
+    pub max_volume_percent: i32,
+}
+
+impl VolumeCap {
+    fn is_active(&self, time: NaiveTime) -> bool {
+        match self.from.cmp(&self.to) {
+            std::cmp::Ordering::Less => self.from <= time && time < self.to,
+            std::cmp::Ordering::Greater => time >= self.from || time < self.to,
+            std::cmp::Ordering::Equal => true,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct VolumeCaps(Vec<VolumeCap>);
+
+impl VolumeCaps {
+    pub fn parse(text: &str) -> Result<Self, anyhow::Error> {
+        let caps: Vec<VolumeCap> = serde_yaml::from_str(text).context("Parse volume caps")?;
+        if let Some(cap) = caps
+            .iter()
+            .find(|cap| !(0..=100).contains(&cap.max_volume_percent))
+        {
+            return Err(anyhow!("Volume cap out of 0..=100: {cap:?}"));
+        }
+        Ok(VolumeCaps(caps))
+    }
+
+    /// No caps if the file doesn't exist.
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => {
+                let caps = VolumeCaps::parse(&text).context(format!("Load {}", path.display()))?;
+                info!("Volume caps: {:?}", caps.0);
+                Ok(caps)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!("{} not found, volume not capped", path.display());
+                Ok(VolumeCaps::default())
+            }
+            Err(e) => Err(anyhow!(e).context(format!("Read {}", path.display()))),
+        }
+    }
+
+    /// The strictest of the caps active at that time.
+    pub fn active_at(&self, time: NaiveTime) -> Option<&VolumeCap> {
+        self.0
+            .iter()
+            .filter(|cap| cap.is_active(time))
+            .min_by_key(|cap| cap.max_volume_percent)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn time(text: &str) -> NaiveTime {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn active_at_test() {
+        let caps = VolumeCaps::parse(
+            r#"
+- from: "22:00:00"
+  to: "08:00:00"
+  max_volume_percent: 40
+- from: "23:30:00"
+  to: "06:00:00"
+  max_volume_percent: 20
+- from: "12:00:00"
+  to: "13:00:00"
+  max_volume_percent: 70
+"#,
+        )
+        .unwrap();
+        assert_eq!(caps.active_at(time("21:59:59")), None);
+        assert_eq!(
+            caps.active_at(time("22:00:00")).unwrap().max_volume_percent,
+            40
+        );
+        assert_eq!(
+            caps.active_at(time("01:00:00")).unwrap().max_volume_percent,
+            20
+        );
+        assert_eq!(
+            caps.active_at(time("12:30:00")).unwrap().max_volume_percent,
+            70
+        );
+        assert_eq!(caps.active_at(time("08:00:00")), None);
+        assert!(
+            VolumeCaps::parse("- {from: '1:00:00', to: '2:00:00', max_volume_percent: 120}")
+                .is_err()
+        );
+    }
+}
@@ -1,66 +1,584 @@
+use crate::saved_volume::SavedVolume;
+use crate::volume_backend::VolumeBackend;
+use crate::volume_caps::{VolumeCap, VolumeCaps};
+use crate::volume_history::{VolumeAction, VolumeEvent, VolumeHistory};
 use anyhow::{anyhow, Context};
+use chrono::Local;
 use log::*;
-use regex::Regex;
-use std::process::Command;
-use std::sync::Mutex;
+use serde::Serialize;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct VolumeStatus {
+    pub volume_percent: i32,
+    pub muted: bool,
+    /// The quiet hours cap in force right now.
+    pub cap: Option<VolumeCap>,
+}
+
+const RAMP_STEP: Duration = Duration::from_millis(100);
+
+#[derive(Default)]
+struct VolumeState {
+    /// Level to restore on unmute, set while muted.
+    muted_volume: Option<i32>,
+    /// Bumped by every volume request, a running ramp stops once it sees a newer one.
+    generation: u64,
+    /// Generation and starting level of the last fade out, for a fade in cutting it short.
+    fade_out: Option<(u64, i32)>,
+}
+
+/// How `fade_out` ended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FadeOut {
+    /// Fading out is off, or the volume is muted.
+    Skipped,
+    /// Faded down to zero from that level.
+    Faded(i32),
+    /// A newer volume request took over before the fade was done.
+    Cancelled,
+}
+
+/// How long playback fades in after it starts and out before it is paused. Zero disables.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct FadePolicy {
+    pub fade_in: Duration,
+    pub fade_out: Duration,
+}
 
 pub struct VolumeController {
-    lock: Mutex<()>,
+    backend: Box<dyn VolumeBackend>,
+    state: Mutex<VolumeState>,
+    fade_policy: FadePolicy,
+    caps: VolumeCaps,
+    saved_volume: Option<SavedVolume>,
+    history: Option<VolumeHistory>,
 }
 
 impl VolumeController {
-    pub fn new() -> VolumeController {
+    pub fn new(
+        backend: Box<dyn VolumeBackend>,
+        fade_policy: FadePolicy,
+        caps: VolumeCaps,
+        saved_volume: Option<SavedVolume>,
+        history: Option<VolumeHistory>,
+    ) -> VolumeController {
         VolumeController {
-            lock: Mutex::new(()),
+            backend,
+            state: Mutex::new(VolumeState::default()),
+            fade_policy,
+            caps,
+            saved_volume,
+            history,
         }
     }
 }
 
 impl VolumeController {
-    pub fn change_volume(self: &VolumeController, delta_percent: i32) -> Result<(), anyhow::Error> {
-        let _guard = self
-            .lock
+    fn lock(&self) -> Result<MutexGuard<'_, VolumeState>, anyhow::Error> {
+        self.state
             .lock()
-            .or_else(|e| Err(anyhow!("VolumeController mutex poisoned: {e:#?}")))?;
-        let vol = get_current_volume().context("Failed to get current volume")?;
-        set_current_volume((vol + delta_percent).clamp(0, 100)).into()
+            .map_err(|e| anyhow!("VolumeController mutex poisoned: {e:#?}"))
     }
-}
 
-fn get_current_volume() -> Result<i32, anyhow::Error> {
-    let output = String::from_utf8(
-        Command::new("amixer")
-            .args(["sget", "SoftMaster"])
-            .output()?
-            .stdout,
-    )
-    .context("Get volume with amixer failed")?;
-    let re = Regex::new(r"\[(?<percent>\d+)%]")?;
-    let caps = re
-        .captures(&*output)
-        .ok_or(anyhow!("Unable to parse current volume from: {:?}", output))?;
-    let percent = &caps["percent"];
-    let result: i32 = percent.parse()?;
-
-    info!("Current volume: {}", result);
-    Ok(result)
-}
+    /// Locks the state for a change of volume, cancelling any running ramp.
+    fn lock_for_change(&self) -> Result<MutexGuard<'_, VolumeState>, anyhow::Error> {
+        let mut state = self.lock()?;
+        state.generation += 1;
+        Ok(state)
+    }
 
-fn set_current_volume(vol: i32) -> Result<(), anyhow::Error> {
-    let vol_percent = vol.to_string() + "%";
-
-    match Command::new("amixer")
-        .args(["sset", "SoftMaster", &*vol_percent])
-        .status()
-    {
-        Ok(exit_status) => {
-            if exit_status.success() {
-                Ok(())
-            } else {
-                Err(anyhow!("amixer exit status: {}", exit_status))
-                    .context("Set current volume failed")
+    fn active_cap(&self) -> Option<&VolumeCap> {
+        self.caps.active_at(Local::now().time())
+    }
+
+    /// Every volume change goes through here, so no request gets past the quiet hours cap.
+    /// Returns the volume actually set.
+    fn set_capped_volume(&self, percent: i32) -> Result<i32, anyhow::Error> {
+        let percent = match self.active_cap() {
+            Some(cap) if percent > cap.max_volume_percent => {
+                info!("Volume {percent}% capped by {cap:?}");
+                cap.max_volume_percent
             }
+            _ => percent,
+        };
+        self.backend.set_volume(percent)?;
+        Ok(percent)
+    }
+
+    /// Keeps a level people chose for the next start.
+    fn remember(&self, percent: i32) {
+        if let Some(saved_volume) = &self.saved_volume {
+            saved_volume
+                .save(percent)
+                .unwrap_or_else(|e| error!("Failed to save volume {percent}%: {e:#}"));
+        }
+    }
+
+    /// Keeps track of who changed the volume.
+    fn record(&self, client: &str, action: VolumeAction, old: Option<i32>, new: i32) {
+        if let Some(history) = &self.history {
+            history.record(VolumeEvent {
+                at: Local::now(),
+                client: client.to_string(),
+                action,
+                old_volume_percent: old,
+                new_volume_percent: new,
+            });
+        }
+    }
+
+    /// Oldest first, empty without a history.
+    pub fn history(&self) -> Vec<VolumeEvent> {
+        self.history
+            .as_ref()
+            .map(VolumeHistory::list)
+            .unwrap_or_default()
+    }
+
+    /// Sets the volume saved before the last shutdown.
+    pub fn restore_saved_volume(&self) -> Result<(), anyhow::Error> {
+        let Some(saved_volume) = &self.saved_volume else {
+            return Ok(());
+        };
+        let percent = saved_volume.load()?;
+        let _state = self.lock_for_change()?;
+        let old = self.backend.get_volume().ok();
+        let percent = self.set_capped_volume(percent)?;
+        info!("Restored volume {percent}%");
+        self.record("startup", VolumeAction::Restore, old, percent);
+        Ok(())
+    }
+
+    fn get_current_volume(&self) -> Result<i32, anyhow::Error> {
+        let result = self.backend.get_volume()?;
+        info!("Current volume: {}", result);
+        Ok(result)
+    }
+
+    /// Changing the volume while muted unmutes, starting from the level before muting.
+    /// `client` is whoever asked for it, kept in the history.
+    pub fn change_volume(
+        self: &VolumeController,
+        delta_percent: i32,
+        client: &str,
+    ) -> Result<(), anyhow::Error> {
+        let mut state = self.lock_for_change()?;
+        let old = self
+            .get_current_volume()
+            .context("Failed to get current volume")?;
+        let vol = state.muted_volume.unwrap_or(old);
+        let vol = self.set_capped_volume((vol + delta_percent).clamp(0, 100))?;
+        state.muted_volume = None;
+        self.remember(vol);
+        self.record(client, VolumeAction::Change, Some(old), vol);
+        Ok(())
+    }
+
+    pub fn set_volume(
+        self: &VolumeController,
+        percent: i32,
+        client: &str,
+    ) -> Result<(), anyhow::Error> {
+        if !(0..=100).contains(&percent) {
+            return Err(anyhow!("Volume out of 0..=100: {percent}"));
         }
-        Err(err) => Err(anyhow!(err).context("Set volume with amixer failed")),
+        let mut state = self.lock_for_change()?;
+        let old = self.backend.get_volume().ok();
+        let percent = self.set_capped_volume(percent)?;
+        state.muted_volume = None;
+        self.remember(percent);
+        self.record(client, VolumeAction::Set, old, percent);
+        Ok(())
+    }
+
+    pub fn get_volume(self: &VolumeController) -> Result<i32, anyhow::Error> {
+        let _guard = self.lock()?;
+        self.get_current_volume()
+            .context("Failed to get current volume")
+    }
+
+    pub fn get_status(self: &VolumeController) -> Result<VolumeStatus, anyhow::Error> {
+        let state = self.lock()?;
+        Ok(VolumeStatus {
+            volume_percent: self
+                .get_current_volume()
+                .context("Failed to get current volume")?,
+            muted: state.muted_volume.is_some(),
+            cap: self.active_cap().cloned(),
+        })
+    }
+
+    pub fn mute(self: &VolumeController, client: &str) -> Result<(), anyhow::Error> {
+        let mut state = self.lock_for_change()?;
+        if state.muted_volume.is_some() {
+            return Ok(());
+        }
+        let vol = self
+            .get_current_volume()
+            .context("Failed to get current volume")?;
+        self.set_capped_volume(0)?;
+        info!("Muted, volume was {vol}");
+        state.muted_volume = Some(vol);
+        self.record(client, VolumeAction::Mute, Some(vol), 0);
+        Ok(())
+    }
+
+    pub fn unmute(self: &VolumeController, client: &str) -> Result<(), anyhow::Error> {
+        let mut state = self.lock_for_change()?;
+        if let Some(vol) = state.muted_volume {
+            let old = self.backend.get_volume().ok();
+            let vol = self.set_capped_volume(vol)?;
+            info!("Unmuted, volume restored to {vol}");
+            state.muted_volume = None;
+            self.remember(vol);
+            self.record(client, VolumeAction::Unmute, old, vol);
+        }
+        Ok(())
+    }
+
+    pub fn toggle_mute(self: &VolumeController, client: &str) -> Result<(), anyhow::Error> {
+        let muted = self.lock()?.muted_volume.is_some();
+        match muted {
+            true => self.unmute(client),
+            false => self.mute(client),
+        }
+    }
+
+    /// Lowers the volume, or the level restored on unmute, to the cap of each quiet hours window
+    /// as it begins.
+    pub async fn run_caps(&self) {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(10));
+        info!("Running volume caps");
+        let mut last_cap = None;
+        loop {
+            interval.tick().await;
+            let cap = self.active_cap().cloned();
+            if cap == last_cap {
+                continue;
+            }
+            if let Some(cap) = &cap {
+                info!("Volume cap {cap:?} begins");
+                self.apply_cap(cap.max_volume_percent)
+                    .unwrap_or_else(|e| error!("Failed to apply volume cap: {e:#}"));
+            }
+            last_cap = cap;
+        }
+    }
+
+    fn apply_cap(&self, max_volume_percent: i32) -> Result<(), anyhow::Error> {
+        let mut state = self.lock()?;
+        if let Some(muted_volume) = &mut state.muted_volume {
+            *muted_volume = (*muted_volume).min(max_volume_percent);
+        } else if self.get_current_volume()? > max_volume_percent {
+            self.set_capped_volume(max_volume_percent)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the volume to `target` over `duration` on a background task.
+    pub fn start_ramp(
+        self: &Arc<VolumeController>,
+        target: i32,
+        duration: Duration,
+        client: &str,
+    ) -> Result<(), anyhow::Error> {
+        if !(0..=100).contains(&target) {
+            return Err(anyhow!("Volume out of 0..=100: {target}"));
+        }
+        let generation = {
+            let mut state = self.lock_for_change()?;
+            state.muted_volume = None;
+            self.record(
+                client,
+                VolumeAction::Ramp,
+                self.backend.get_volume().ok(),
+                target,
+            );
+            state.generation
+        };
+        self.remember(target);
+        self.spawn_ramp(generation, target, duration);
+        Ok(())
+    }
+
+    /// Call right after playback starts. Drops the volume to zero and ramps it back up to
+    /// `target`, or to the level it had, over the configured fade in time.
+    pub fn fade_in(
+        self: &Arc<VolumeController>,
+        target: Option<i32>,
+        client: &str,
+    ) -> Result<(), anyhow::Error> {
+        if self.fade_policy.fade_in.is_zero() {
+            return match target {
+                Some(target) => self.set_volume(target, client),
+                None => Ok(()),
+            };
+        }
+        let (generation, target) = {
+            let mut state = self.lock_for_change()?;
+            if state.muted_volume.is_some() && target.is_none() {
+                return Ok(());
+            }
+            let current = self.get_current_volume()?;
+            let target = match target {
+                Some(target) => {
+                    self.remember(target);
+                    self.record(client, VolumeAction::FadeIn, Some(current), target);
+                    target
+                }
+                // Cutting a fade out short goes back to where it started from.
+                None => match state.fade_out {
+                    Some((generation, from)) if generation + 1 == state.generation => from,
+                    _ => current,
+                },
+            };
+            self.set_capped_volume(0)?;
+            state.muted_volume = None;
+            (state.generation, target)
+        };
+        self.spawn_ramp(generation, target, self.fade_policy.fade_in);
+        Ok(())
+    }
+
+    /// Call right before playback is paused. Ramps the volume down to zero over the configured
+    /// fade out time. The level it started from is to be restored after the pause. If the fade
+    /// is cancelled, whoever cancelled it wants playback, so it shouldn't be paused.
+    pub async fn fade_out(&self) -> Result<FadeOut, anyhow::Error> {
+        if self.fade_policy.fade_out.is_zero() {
+            return Ok(FadeOut::Skipped);
+        }
+        let (generation, from) = {
+            let mut state = self.lock_for_change()?;
+            if state.muted_volume.is_some() {
+                return Ok(FadeOut::Skipped);
+            }
+            let from = self.get_current_volume()?;
+            state.fade_out = Some((state.generation, from));
+            (state.generation, from)
+        };
+        match self.ramp(generation, 0, self.fade_policy.fade_out).await? {
+            true => Ok(FadeOut::Faded(from)),
+            false => Ok(FadeOut::Cancelled),
+        }
+    }
+
+    /// Puts back the level `fade_out` started from, unless the volume was changed since.
+    pub fn restore_after_fade_out(&self, volume: i32) -> Result<(), anyhow::Error> {
+        let mut state = self.lock_for_change()?;
+        if self.backend.get_volume()? == 0 && state.muted_volume.is_none() {
+            self.set_capped_volume(volume)?;
+            state.muted_volume = None;
+        }
+        Ok(())
+    }
+
+    fn spawn_ramp(self: &Arc<VolumeController>, generation: u64, target: i32, duration: Duration) {
+        let this = self.clone();
+        tokio::task::spawn(async move {
+            if let Err(e) = this.ramp(generation, target, duration).await {
+                error!("Volume ramp to {target}% failed: {e:#}");
+            }
+        });
+    }
+
+    /// Returns false if a newer volume request cancelled the ramp.
+    async fn ramp(
+        &self,
+        generation: u64,
+        target: i32,
+        duration: Duration,
+    ) -> Result<bool, anyhow::Error> {
+        let from = self.get_current_volume()?;
+        info!("Ramp volume from {from}% to {target}% over {duration:?}");
+        let steps = (duration.as_millis() / RAMP_STEP.as_millis()).max(1) as i32;
+        let mut last = from;
+        for step in 1..=steps {
+            tokio::time::sleep(RAMP_STEP).await;
+            let _state = match self.lock()? {
+                state if state.generation == generation => state,
+                _ => {
+                    info!("Ramp to {target}% cancelled at {last}%");
+                    return Ok(false);
+                }
+            };
+            let volume = from + (target - from) * step / steps;
+            if volume != last {
+                self.set_capped_volume(volume)?;
+                last = volume;
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::volume_backend::FakeVolumeBackend;
+
+    #[test]
+    fn mute_restores_volume_test() {
+        let volume_controller = VolumeController::new(
+            Box::new(FakeVolumeBackend::new(40)),
+            FadePolicy::default(),
+            VolumeCaps::default(),
+            None,
+            None,
+        );
+        volume_controller.toggle_mute("test").unwrap();
+        assert_eq!(
+            volume_controller.get_status().unwrap(),
+            VolumeStatus {
+                volume_percent: 0,
+                muted: true,
+                cap: None,
+            }
+        );
+        volume_controller.mute("test").unwrap();
+        volume_controller.unmute("test").unwrap();
+        assert_eq!(volume_controller.get_volume().unwrap(), 40);
+
+        volume_controller.mute("test").unwrap();
+        volume_controller.change_volume(5, "test").unwrap();
+        assert_eq!(volume_controller.get_volume().unwrap(), 45);
+        assert!(!volume_controller.get_status().unwrap().muted);
+
+        volume_controller.change_volume(100, "test").unwrap();
+        assert_eq!(volume_controller.get_volume().unwrap(), 100);
+        assert!(volume_controller.set_volume(101, "test").is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ramp_cancelled_by_newer_request_test() {
+        let volume_controller = Arc::new(VolumeController::new(
+            Box::new(FakeVolumeBackend::new(40)),
+            FadePolicy {
+                fade_in: Duration::from_secs(1),
+                fade_out: Duration::from_secs(1),
+            },
+            VolumeCaps::default(),
+            None,
+            None,
+        ));
+        volume_controller.fade_in(Some(80), "test").unwrap();
+        assert_eq!(volume_controller.get_volume().unwrap(), 0);
+        tokio::time::sleep(Duration::from_millis(550)).await;
+        assert_eq!(volume_controller.get_volume().unwrap(), 40);
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(volume_controller.get_volume().unwrap(), 80);
+
+        assert_eq!(
+            volume_controller.fade_out().await.unwrap(),
+            FadeOut::Faded(80)
+        );
+        assert_eq!(volume_controller.get_volume().unwrap(), 0);
+        volume_controller.restore_after_fade_out(80).unwrap();
+        assert_eq!(volume_controller.get_volume().unwrap(), 80);
+
+        volume_controller
+            .start_ramp(20, Duration::from_secs(10), "test")
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(1050)).await;
+        volume_controller.set_volume(50, "test").unwrap();
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(volume_controller.get_volume().unwrap(), 50);
+    }
+
+    #[test]
+    fn capped_volume_test() {
+        let volume_controller = VolumeController::new(
+            Box::new(FakeVolumeBackend::new(60)),
+            FadePolicy::default(),
+            VolumeCaps::parse("- {from: '00:00:00', to: '00:00:00', max_volume_percent: 40}")
+                .unwrap(),
+            None,
+            None,
+        );
+        volume_controller.mute("test").unwrap();
+        volume_controller.apply_cap(40).unwrap();
+        volume_controller.unmute("test").unwrap();
+        assert_eq!(volume_controller.get_volume().unwrap(), 40);
+        volume_controller.set_volume(90, "test").unwrap();
+        assert_eq!(volume_controller.get_volume().unwrap(), 40);
+        volume_controller.change_volume(-5, "test").unwrap();
+        assert_eq!(volume_controller.get_volume().unwrap(), 35);
+        assert_eq!(
+            volume_controller
+                .get_status()
+                .unwrap()
+                .cap
+                .unwrap()
+                .max_volume_percent,
+            40
+        );
+    }
+
+    #[test]
+    fn saved_volume_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let saved_volume = SavedVolume {
+            path: dir.path().join("volume.json"),
+            default_percent: 60,
+            floor_percent: None,
+            ceiling_percent: None,
+        };
+        let volume_controller = VolumeController::new(
+            Box::new(FakeVolumeBackend::new(100)),
+            FadePolicy::default(),
+            VolumeCaps::default(),
+            Some(saved_volume.clone()),
+            None,
+        );
+        volume_controller.restore_saved_volume().unwrap();
+        assert_eq!(volume_controller.get_volume().unwrap(), 60);
+        volume_controller.change_volume(-5, "test").unwrap();
+        volume_controller.mute("test").unwrap();
+        assert_eq!(saved_volume.load().unwrap(), 55);
+
+        let restarted = VolumeController::new(
+            Box::new(FakeVolumeBackend::new(100)),
+            FadePolicy::default(),
+            VolumeCaps::default(),
+            Some(saved_volume),
+            None,
+        );
+        restarted.restore_saved_volume().unwrap();
+        assert_eq!(restarted.get_volume().unwrap(), 55);
+    }
+
+    #[test]
+    fn history_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let volume_controller = VolumeController::new(
+            Box::new(FakeVolumeBackend::new(30)),
+            FadePolicy::default(),
+            VolumeCaps::default(),
+            None,
+            Some(VolumeHistory::load(&dir.path().join("history.jsonl"), 10).unwrap()),
+        );
+        volume_controller.change_volume(70, "10.0.0.2").unwrap();
+        volume_controller.mute("10.0.0.3").unwrap();
+        volume_controller.get_volume().unwrap();
+        let history: Vec<_> = volume_controller
+            .history()
+            .into_iter()
+            .map(|e| {
+                (
+                    e.client,
+                    e.action,
+                    e.old_volume_percent,
+                    e.new_volume_percent,
+                )
+            })
+            .collect();
+        assert_eq!(
+            history,
+            [
+                ("10.0.0.2".to_string(), VolumeAction::Change, Some(30), 100),
+                ("10.0.0.3".to_string(), VolumeAction::Mute, Some(100), 0),
+            ]
+        );
     }
 }
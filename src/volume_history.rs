@@ -0,0 +1,169 @@
+use crate::atomic_write::write_atomically;
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Local};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum VolumeAction {
+    Change,
+    Set,
+    Ramp,
+    FadeIn,
+    Mute,
+    Unmute,
+    Restore,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct VolumeEvent {
+    pub at: DateTime<Local>,
+    /// Address of whoever asked for the change.
+    pub client: String,
+    pub action: VolumeAction,
+    /// `None` if it couldn't be read before the change.
+    pub old_volume_percent: Option<i32>,
+    pub new_volume_percent: i32,
+}
+
+struct HistoryState {
+    events: VecDeque<VolumeEvent>,
+    lines_in_file: usize,
+}
+
+/// The last volume changes with who made them. Kept in memory and appended to a json lines
+/// file, which is rewritten with only the kept events once it grows to twice their number.
+pub struct VolumeHistory {
+    path: PathBuf,
+    capacity: usize,
+    state: Mutex<HistoryState>,
+}
+
+impl VolumeHistory {
+    /// Starts empty if the file doesn't exist.
+    pub fn load(path: &Path, capacity: usize) -> Result<Self, anyhow::Error> {
+        let mut events = VecDeque::new();
+        let mut lines_in_file = 0;
+        match std::fs::read_to_string(path) {
+            Ok(text) => {
+                for line in text.lines().filter(|line| !line.trim().is_empty()) {
+                    lines_in_file += 1;
+                    match serde_json::from_str(line) {
+                        Ok(event) => events.push_back(event),
+                        Err(e) => warn!("Skipping volume event {line:?}: {e}"),
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!("{} not found, no volume history", path.display());
+            }
+            Err(e) => return Err(anyhow!(e).context(format!("Read {}", path.display()))),
+        }
+        while events.len() > capacity {
+            events.pop_front();
+        }
+        Ok(VolumeHistory {
+            path: path.to_path_buf(),
+            capacity,
+            state: Mutex::new(HistoryState {
+                events,
+                lines_in_file,
+            }),
+        })
+    }
+
+    pub fn record(&self, event: VolumeEvent) {
+        info!("Volume event {event:?}");
+        let mut state = self.state.lock().unwrap();
+        state.events.push_back(event.clone());
+        while state.events.len() > self.capacity {
+            state.events.pop_front();
+        }
+        let result = match state.lines_in_file + 1 >= 2 * self.capacity {
+            true => self.rewrite(&state.events).map(|_| state.events.len()),
+            false => self.append(&event).map(|_| state.lines_in_file + 1),
+        };
+        match result {
+            Ok(lines_in_file) => state.lines_in_file = lines_in_file,
+            Err(e) => error!(
+                "Failed to write volume history to {}: {e:#}",
+                self.path.display()
+            ),
+        }
+    }
+
+    /// Oldest first.
+    pub fn list(&self) -> Vec<VolumeEvent> {
+        self.state.lock().unwrap().events.iter().cloned().collect()
+    }
+
+    fn dir(&self) -> &Path {
+        match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        }
+    }
+
+    fn append(&self, event: &VolumeEvent) -> Result<(), anyhow::Error> {
+        std::fs::create_dir_all(self.dir())?;
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .context(format!("Open {}", self.path.display()))?;
+        writeln!(file, "{}", serde_json::to_string(event)?)?;
+        Ok(())
+    }
+
+    fn rewrite(&self, events: &VecDeque<VolumeEvent>) -> Result<(), anyhow::Error> {
+        let mut contents = String::new();
+        for event in events {
+            contents += &serde_json::to_string(event)?;
+            contents.push('\n');
+        }
+        write_atomically(&self.path, contents.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn event(new_volume_percent: i32) -> VolumeEvent {
+        VolumeEvent {
+            at: Local::now(),
+            client: "192.168.1.7".to_string(),
+            action: VolumeAction::Set,
+            old_volume_percent: None,
+            new_volume_percent,
+        }
+    }
+
+    #[test]
+    fn bounded_history_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history/volume.jsonl");
+        let history = VolumeHistory::load(&path, 3).unwrap();
+        for volume in 0..10 {
+            history.record(event(volume));
+        }
+        let volumes = |history: &VolumeHistory| {
+            history
+                .list()
+                .iter()
+                .map(|e| e.new_volume_percent)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(volumes(&history), [7, 8, 9]);
+        assert!(std::fs::read_to_string(&path).unwrap().lines().count() < 6);
+
+        let reloaded = VolumeHistory::load(&path, 2).unwrap();
+        assert_eq!(volumes(&reloaded), [8, 9]);
+        assert_eq!(reloaded.list()[1], history.list()[2]);
+    }
+}